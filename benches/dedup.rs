@@ -0,0 +1,41 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use file_cleaner::{deduplicate, Options, Walk};
+use std::fs::write;
+use tempfile::TempDir;
+
+const FILES: usize = 200;
+const FILE_SIZE: usize = 256 * 1024;
+
+/// `FILES` files of `FILE_SIZE` bytes, with `FILES / copies` distinct contents.
+fn synthetic_dir(copies: usize) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    for i in 0..FILES {
+        let mut contents = vec![0u8; FILE_SIZE];
+        contents[..8].copy_from_slice(&(i / copies).to_le_bytes());
+        write(dir.path().join(i.to_string()), contents).unwrap();
+    }
+    dir
+}
+
+fn bench(c: &mut Criterion) {
+    // Dry runs, so every iteration sees the same files.
+    let options = Options {
+        dry_run: true,
+        ..Default::default()
+    };
+    for (name, copies) in [("unique", 1), ("duplicates", 10)] {
+        let dir = synthetic_dir(copies);
+        c.bench_function(name, |b| {
+            b.iter(|| deduplicate(Walk::new([dir.path()], false).unwrap(), &options).unwrap())
+        });
+    }
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
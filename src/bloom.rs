@@ -0,0 +1,74 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Bloom filter over file fingerprints, see [`crate::Options::bloom`]. Only ever rules matches out.
+
+use crate::{read_full, Io, ID};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// Memory taken by the filter: with [`HASHES`] bits per file, about 2% of unique files still go through a full read at a million files.
+pub(crate) const BYTES: usize = 1 << 20;
+
+/// Bits set per fingerprint.
+const HASHES: u64 = 4;
+
+/// Bytes at the end of a file that go into its fingerprint: one page, like the default prefix of its [`ID`].
+const TAIL: u64 = 4096;
+
+pub(crate) struct Bloom {
+    bits: Vec<AtomicU64>,
+}
+
+impl Bloom {
+    pub(crate) fn new() -> Self {
+        Self {
+            bits: (0..BYTES / 8).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Adds `fingerprint`, returning whether it may have been added before; `false` means it definitely wasn't.
+    /// Callers serialize inserts of equal fingerprints, otherwise both could see `false`.
+    pub(crate) fn insert(&self, fingerprint: u64) -> bool {
+        // Double hashing: the bit positions are h1, h1 + h2, h1 + 2 h2… spread by a second, odd hash.
+        let h1 = fingerprint;
+        let h2 = fingerprint
+            .rotate_left(32)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            | 1;
+        let len = self.bits.len() as u64 * 64;
+        let mut seen = true;
+        for i in 0..HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % len;
+            let mask = 1 << (bit % 64);
+            seen &= self.bits[(bit / 64) as usize].fetch_or(mask, Relaxed) & mask != 0;
+        }
+        seen
+    }
+}
+
+/// Hashes `id`, the compared size and the last [`TAIL`] of those bytes: files with different fingerprints can't be identical,
+/// and reading it takes one seek and one page, where confirming a match reads every byte.
+pub(crate) fn fingerprint(
+    path: &Path,
+    id: ID,
+    size: u64,
+    limit: u64,
+    io: Io<'_>,
+) -> Result<u64, Error> {
+    let size = size.min(limit);
+    let mut file = io.open(path)?;
+    let start = size.saturating_sub(TAIL);
+    retry_transient!(io, file.seek(SeekFrom::Start(start)))?;
+    let mut buf = [0u8; TAIL as usize];
+    let len = read_full(&mut io.reader(&mut file).take(size - start), &mut buf)?;
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    hasher.update(&id.to_le_bytes());
+    hasher.update(&size.to_le_bytes());
+    hasher.update(&buf[..len]);
+    Ok(hasher.digest())
+}
@@ -0,0 +1,154 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Defaults read from a TOML file, which command line flags then override.
+
+use crate::Size;
+use file_cleaner::{Action, Options, PathError};
+use globset::{Glob, GlobSetBuilder};
+use serde::Deserialize;
+use std::io::{Error, ErrorKind};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+/// Keys are named after the flags they set, e.g. `min-size = "1M"` for --min-size.
+/// `exclude` and `ext` are lists, which flags add to rather than replace.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    recursive: Option<bool>,
+    dry_run: Option<bool>,
+    /// One of "delete", "trash", "hardlink", "symlink", or "quarantine" into `delete-to`.
+    action: Option<String>,
+    /// Like --delete-to, sets the action to "quarantine" unless `action` says otherwise.
+    delete_to: Option<PathBuf>,
+    keep: Option<String>,
+    threads: Option<NonZeroUsize>,
+    min_size: Option<ConfigSize>,
+    max_size: Option<ConfigSize>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    ext: Vec<String>,
+    include_hidden: Option<bool>,
+    follow_symlinks: Option<bool>,
+    same_device: Option<bool>,
+}
+
+/// A byte count, either as a number or a string like "500K".
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigSize {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Config {
+    /// Reads `path`, or the default location if `None`; only a missing default file is fine.
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound && !explicit => {
+                return Ok(Self::default())
+            }
+            Err(err) => return Err(PathError::wrap("reading", &path, err)),
+        };
+        toml::from_str(&text).map_err(|err| {
+            PathError::wrap("parsing", &path, Error::new(ErrorKind::InvalidData, err))
+        })
+    }
+
+    /// Applies every key that's set, for the command line to override afterwards.
+    pub fn apply(
+        self,
+        options: &mut Options,
+        recursive: &mut bool,
+        exclude: &mut GlobSetBuilder,
+    ) -> Result<(), Error> {
+        let invalid = |key: &str, value: &str| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value for {key} in the config: {value}"),
+            )
+        };
+        let size = |key: &str, size: ConfigSize| match size {
+            ConfigSize::Bytes(bytes) => Ok(bytes),
+            ConfigSize::Text(text) => text
+                .parse::<Size>()
+                .map(|size| size.0)
+                .map_err(|()| invalid(key, &text)),
+        };
+
+        if let Some(value) = self.recursive {
+            *recursive = value;
+        }
+        if let Some(value) = self.dry_run {
+            options.dry_run = value;
+        }
+        if let Some(dir) = self.delete_to {
+            options.action = Action::Quarantine;
+            options.quarantine = Some(dir);
+        }
+        if let Some(value) = self.action {
+            options.action = value.parse().map_err(|()| invalid("action", &value))?;
+        }
+        if let Some(value) = self.keep {
+            options.keep = value.parse().map_err(|()| invalid("keep", &value))?;
+        }
+        if let Some(value) = self.threads {
+            options.threads = value;
+        }
+        if let Some(value) = self.min_size {
+            options.min_size = size("min-size", value)?;
+        }
+        if let Some(value) = self.max_size {
+            options.max_size = size("max-size", value)?;
+        }
+        for pattern in self.exclude {
+            exclude.add(Glob::new(&pattern).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("exclude in the config: {err}"),
+                )
+            })?);
+        }
+        options.extensions.extend(
+            self.ext
+                .into_iter()
+                .map(|ext| ext.trim_start_matches('.').to_owned()),
+        );
+        if let Some(value) = self.include_hidden {
+            options.include_hidden = value;
+        }
+        if let Some(value) = self.follow_symlinks {
+            options.follow_symlinks = value;
+        }
+        if let Some(value) = self.same_device {
+            options.same_device = value;
+        }
+        Ok(())
+    }
+}
+
+/// `file_cleaner.toml` in the user's config directory: `$XDG_CONFIG_HOME`, `~/.config`, or `%APPDATA%` on Windows.
+fn default_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    };
+    Some(dir?.join("file_cleaner.toml"))
+}
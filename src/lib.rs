@@ -0,0 +1,3149 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Finds files with identical contents and removes (or otherwise disposes of) all but one copy.
+//!
+//! [`deduplicate`] does the work; the `file_cleaner` binary is a thin CLI around it.
+//!
+//! Diagnostics go through the [`log`] facade: errors and skipped files are logged at `error` and `warn`,
+//! duplicates acted upon at `info`, and unique files at `debug`.
+//!
+//! Paths beyond Windows' legacy 260 character MAX_PATH, and UNC shares, need no special handling:
+//! std already switches to the extended-length `\\?\` form on its own where needed.
+
+mod similar;
+
+pub use similar::{find_similar, SimilarGroup, SimilarSummary};
+
+use globset::GlobSet;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use nohash_hasher::IntMap as HashMap;
+use std::collections::BTreeMap;
+use std::fs::{read_dir, remove_file, File, ReadDir};
+use std::io::{Error, ErrorKind};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// xxh3 of the first [`Options::prefix_bytes`] of a file, 64 bits wide on every target;
+/// mixed with the size where those are all zero.
+pub type ID = u64;
+/// Every read of contents fills a buffer this large, or [`ID_PREFIX`] once per file for IDs, so reads stay unbuffered:
+/// a `BufReader` passes reads at least as large as its own buffer straight through, 19 reads for 1 MiB either way,
+/// and reading an ID through one costs as many reads for twice the bytes.
+const BUFSIZE: usize = 64 * 1024;
+
+/// Paths per batch with [`Options::shard_by_size`], unless a single size class has more.
+const SHARD_FILES: usize = 1 << 16;
+
+/// Default [`Options::prefix_bytes`]. Reading one page costs about as much as reading 8 bytes, and tells far more files apart.
+pub const ID_PREFIX: NonZeroU64 = NonZeroU64::new(4096).unwrap();
+
+/// A file no identical copy was found for (yet).
+struct Unique {
+    size: u64,
+    inode: Inode,
+    /// When it was scanned, to tell whether it changed since, see [`changed_since`].
+    modified: Option<SystemTime>,
+    /// Where it came among the paths scanned.
+    order: usize,
+    path: PathBuf,
+    /// [`Options::digest`] of the contents, computed the first time another file of the same size shares its bucket,
+    /// so files with a unique ID are never read in full and each file is hashed at most once; or given, see [`deduplicate_hashed`].
+    hash: Option<Hash>,
+    /// Duplicates of this file that were acted upon, only tracked with [`Options::groups`].
+    duplicates: Vec<PathBuf>,
+    /// Duplicates found with [`Options::min_copies`] above 2, waiting for [`prune`] to tell whether there are enough of them.
+    pending: Vec<Pending>,
+    /// Under one of [`Options::references`], so never acted upon.
+    reference: bool,
+    /// Indices into [`Options::protect`] of the roots holding a surviving copy of this file, once per copy.
+    /// Copies left in place by [`Options::protect`] survive besides `path`, so a root may hold several.
+    protected: Vec<usize>,
+}
+
+impl Unique {
+    fn new(
+        path: PathBuf,
+        metadata: &std::fs::Metadata,
+        order: usize,
+        hash: Option<Hash>,
+        reference: bool,
+        protected: Vec<usize>,
+    ) -> Self {
+        Self {
+            size: metadata.len(),
+            inode: Inode::of(metadata),
+            modified: metadata.modified().ok(),
+            order,
+            path,
+            hash,
+            duplicates: Vec::new(),
+            pending: Vec::new(),
+            reference,
+            protected,
+        }
+    }
+}
+
+/// A copy of a [`Unique`] held back until [`prune`] knows how many there are, see [`Options::min_copies`].
+struct Pending {
+    path: PathBuf,
+    size: u64,
+    /// When it was scanned, so [`prune`] can tell whether it changed since.
+    modified: Option<SystemTime>,
+    /// Left in place by [`Options::protect`] either way, it only counts towards the size of the group.
+    protected: bool,
+}
+
+/// Output of either [`Digest`].
+type Hash = [u8; 32];
+
+/// All unique files sharing an ID.
+/// Each bucket has its own lock, so threads only contend when their files share an ID.
+type Bucket = Arc<Mutex<Vec<Unique>>>;
+
+/// Unique files grouped by ID, owned by a single run.
+type Map = Mutex<HashMap<ID, Bucket>>;
+
+/// A path to scan with its place among all of them, and its hash if it came with one, see [`deduplicate_hashed`].
+type Entry = (usize, PathBuf, Option<Hash>);
+
+/// See [`Options::on_action`].
+pub type OnAction = fn(Action, &Path, &Path) -> Result<(), Error>;
+
+/// What to do with a duplicate once it's found.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Delete,
+    /// Move to the OS recycle bin/trash instead of removing permanently.
+    Trash,
+    /// Replace with a hard link to the kept original, so the path stays but the space is reclaimed.
+    Hardlink,
+    /// Replace with a symlink to the kept original, relative where both share a directory above the root.
+    /// Unlike hard links these work across devices and make the kept copy obvious.
+    /// On Windows creating symlinks takes Developer Mode or the symlink privilege.
+    Symlink,
+    /// Move into [`Options::quarantine`] under the same relative path, for review before deleting them for good.
+    Quarantine,
+}
+
+impl Action {
+    /// Label for the final summary line.
+    pub fn label(self, dry_run: bool) -> &'static str {
+        match (self, dry_run) {
+            (Action::Delete, false) => "deleted",
+            (Action::Delete, true) => "would delete",
+            (Action::Trash, false) => "trashed",
+            (Action::Trash, true) => "would trash",
+            (Action::Hardlink, false) => "linked",
+            (Action::Hardlink, true) => "would link",
+            (Action::Symlink, false) => "symlinked",
+            (Action::Symlink, true) => "would symlink",
+            (Action::Quarantine, false) => "quarantined",
+            (Action::Quarantine, true) => "would quarantine",
+        }
+    }
+
+    /// Verb for prompts.
+    pub fn verb(self) -> &'static str {
+        match self {
+            Action::Delete => "delete",
+            Action::Trash => "trash",
+            Action::Hardlink => "link",
+            Action::Symlink => "symlink",
+            Action::Quarantine => "quarantine",
+        }
+    }
+
+    /// Name used in the JSON report.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Delete => "delete",
+            Action::Trash => "trash",
+            Action::Hardlink => "hardlink",
+            Action::Symlink => "symlink",
+            Action::Quarantine => "quarantine",
+        }
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = ();
+
+    /// Parses [`Action::name`].
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "delete" => Ok(Action::Delete),
+            "trash" => Ok(Action::Trash),
+            "hardlink" => Ok(Action::Hardlink),
+            "symlink" => Ok(Action::Symlink),
+            "quarantine" => Ok(Action::Quarantine),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which copy survives when two files are identical.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Keep {
+    /// Whichever comes first among the paths scanned, which follows directory order unless [`Options::sort`] is set.
+    First,
+    /// Earliest modification time (mtime; ctime isn't available on every platform and changes on rename/chmod).
+    Oldest,
+    /// Latest modification time.
+    Newest,
+    /// Fewest bytes in the full path.
+    ShortestPath,
+}
+
+impl std::str::FromStr for Keep {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "first" => Ok(Keep::First),
+            "oldest" => Ok(Keep::Oldest),
+            "newest" => Ok(Keep::Newest),
+            "shortest-path" => Ok(Keep::ShortestPath),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Keep {
+    /// Whether `cur` should be kept over `old`, the copy currently kept; `cur_first` if it came first among the paths.
+    /// Ties and unreadable timestamps keep whichever came first, so the keeper doesn't depend on which worker got there first.
+    fn prefers(self, cur: &Path, old: &Path, cur_first: bool) -> bool {
+        use std::cmp::Ordering;
+
+        let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let order = match self {
+            Keep::First => Ordering::Equal,
+            Keep::Oldest | Keep::Newest => match (mtime(cur), mtime(old)) {
+                (Some(cur), Some(old)) if self == Keep::Oldest => cur.cmp(&old),
+                (Some(cur), Some(old)) => old.cmp(&cur),
+                _ => Ordering::Equal,
+            },
+            Keep::ShortestPath => cur.as_os_str().len().cmp(&old.as_os_str().len()),
+        };
+        order.then(if cur_first {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }) == Ordering::Less
+    }
+}
+
+/// Order to scan paths in, see [`Options::sort`]; ties are broken by path.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Sort {
+    /// File name, byte for byte.
+    Name,
+    /// Modification time, oldest first; files without one come first.
+    Mtime,
+    /// Smallest first; files whose size can't be read come first.
+    Size,
+    /// Full path, byte for byte, so directories are scanned depth-first in name order.
+    Path,
+}
+
+impl std::str::FromStr for Sort {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "name" => Ok(Sort::Name),
+            "mtime" => Ok(Sort::Mtime),
+            "size" => Ok(Sort::Size),
+            "path" => Ok(Sort::Path),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Sort {
+    /// Sorts `paths`, reading the metadata of each once for [`Sort::Mtime`] and [`Sort::Size`].
+    fn sort(self, paths: &mut [PathBuf]) {
+        match self {
+            Sort::Name => paths.sort_by(|a, b| (a.file_name(), a).cmp(&(b.file_name(), b))),
+            Sort::Mtime => paths.sort_by_cached_key(|path| {
+                let mtime = std::fs::metadata(path).and_then(|m| m.modified());
+                (mtime.ok(), path.clone())
+            }),
+            Sort::Size => paths.sort_by_cached_key(|path| {
+                (std::fs::metadata(path).map(|m| m.len()).ok(), path.clone())
+            }),
+            Sort::Path => paths.sort(),
+        }
+    }
+}
+
+/// What happens to empty files, which are all identical but often kept on purpose, e.g. as markers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Empty {
+    /// Count them, but leave them out of deduplication.
+    Skip,
+    /// Deduplicate them like any other file.
+    Dedup,
+    /// Like `Skip`, but also list them in [`Summary::empty_files`].
+    Report,
+}
+
+impl std::str::FromStr for Empty {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "skip" => Ok(Empty::Skip),
+            "dedup" => Ok(Empty::Dedup),
+            "report" => Ok(Empty::Report),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Retries after a transient error: `WouldBlock`, `TimedOut` or `ResourceBusy`, or any error with [`OnError::Retry`].
+/// Each retry waits twice as long as the one before, starting at `delay`.
+/// `Interrupted` calls are always retried right away, however many times it takes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Retry {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay: Duration::from_millis(50),
+        }
+    }
+}
+
+/// See [`Options::on_error`]. Directories [`Walk`] can't read are only ever counted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnError {
+    /// Report and count the error and carry on with the next file; what was being done to this one is left undone.
+    Skip,
+    /// Like `Skip`, but first retry errors of any kind as set by [`Options::retry`], not only transient ones.
+    Retry,
+    /// Stop at the first error and return it from [`deduplicate`].
+    Abort,
+}
+
+impl std::str::FromStr for OnError {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "skip" => Ok(OnError::Skip),
+            "retry" => Ok(OnError::Retry),
+            "abort" => Ok(OnError::Abort),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Answer to an [`Options::confirm`] prompt.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Confirm {
+    Yes,
+    /// Leave this duplicate alone.
+    No,
+    /// Yes to this and every following duplicate, without asking again.
+    All,
+    /// Leave this duplicate alone and stop scanning.
+    Quit,
+}
+
+/// Strong hash used to tell same-ID files apart.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Digest {
+    Blake3,
+    Sha256,
+}
+
+impl std::str::FromStr for Digest {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "blake3" => Ok(Digest::Blake3),
+            "sha256" => Ok(Digest::Sha256),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Only report and count duplicates, nothing is removed from disk.
+    pub dry_run: bool,
+    pub action: Action,
+    /// Where [`Action::Quarantine`] moves duplicates; files already in it are never scanned.
+    pub quarantine: Option<PathBuf>,
+    pub keep: Keep,
+    /// Among two copies, keep the one whose path matches this over one whose path doesn't, whatever [`Options::keep`] says.
+    /// Where both or neither match, the policy decides; references and real files over symlinks still win first.
+    pub keep_path: Option<regex::Regex>,
+    pub threads: NonZeroUsize,
+    /// Most reads in flight at once on each device; hashing and comparing what was read still runs on all [`Options::threads`].
+    ///
+    /// `None` picks per device: 1 for spinning disks, where concurrent reads mostly add seeks, and no limit otherwise.
+    /// Spinning disks are only detected on Linux. Devices are told apart as for [`Options::same_device`].
+    pub io_concurrency: Option<NonZeroUsize>,
+    /// Most files open at once, to stay clear of the process' descriptor limit; workers wait for a slot instead of failing to open.
+    /// Every worker takes 3 at once for the file it's scanning, so values below that act like 3, and fewer than 3 per thread leave threads idle.
+    /// Directories held open by [`Walk`], one per level it's descended, don't count.
+    pub max_open_files: Option<NonZeroUsize>,
+    /// How opens, reads and removals failing with an error that may clear up by itself are retried, e.g. on flaky network mounts.
+    pub retry: Retry,
+    /// Hash computed for files sharing an ID. Different digests always rule a match out.
+    pub digest: Digest,
+    /// Confirm equal digests with a byte-for-byte comparison before acting.
+    ///
+    /// With this set (the default) the digest only rules matches out, and dedup is exact, but every duplicate's original is read again.
+    /// Without it equal digests are trusted: each file is hashed once, and a file changing between reads can't make a non-duplicate match,
+    /// at the (cryptographically negligible) risk of a collision.
+    pub byte_compare: bool,
+    /// Only compare the first this many bytes of each file, treating files that match that far as duplicates.
+    /// A heuristic, e.g. for media whose trailing metadata differs: it can't guarantee the files are actually identical.
+    pub compare_bytes: Option<u64>,
+    /// Leave duplicates alone where either copy looks like it's still being written:
+    /// named like an unfinished download, modified in the last [`RECENT`], resized since it was scanned, or locked by another process.
+    /// Best effort, as a writer may pause for longer and locks are only advisory on Unix.
+    pub skip_open_files: bool,
+    /// Compare each duplicate with its kept copy once more right before acting, leaving it in place if either changed since the scan.
+    /// Costs another read of both files, which matters on live directories where files may be rewritten during a long scan.
+    pub verify_pass: bool,
+    /// What to do about a failed open, read or action, once [`Options::retry`] gave up on it.
+    pub on_error: OnError,
+    /// Files whose name or full path match are neither scanned nor considered for deletion.
+    pub exclude: GlobSet,
+    /// When not empty, only files with one of these extensions are scanned; compared case-insensitively.
+    pub extensions: Vec<String>,
+    /// Only files of at least this many bytes are scanned.
+    pub min_size: u64,
+    /// Only files of at most this many bytes are scanned.
+    pub max_size: u64,
+    /// Only files last modified at or after this time are scanned.
+    pub modified_after: Option<SystemTime>,
+    /// Only files last modified before this time are scanned.
+    pub modified_before: Option<SystemTime>,
+    pub empty: Empty,
+    /// Scan symlinks to files as if they were the file they point to, instead of skipping them with a warning.
+    /// Symlinked directories are never followed.
+    pub follow_symlinks: bool,
+    /// Also scan hidden files: dotfiles, and on Windows files with the hidden attribute.
+    /// Hidden directories are skipped by [`Walk`] unless told otherwise with [`Walk::include_hidden`].
+    pub include_hidden: bool,
+    /// Only treat files in the same directory as duplicates, keeping one copy per directory.
+    pub per_dir: bool,
+    /// Only treat files with the same name, ignoring case, as duplicates: e.g. `README.md` and `readme.md` with the same contents.
+    pub by_name: bool,
+    /// Only act on copies with the same extension, ignoring case: e.g. `backup.tar` and `backup.tar.bak` with the same contents are both kept.
+    /// Such copies are still compared, and counted in [`Summary::other_ext`].
+    pub same_ext_only: bool,
+    /// Only treat files on the same filesystem as duplicates. Always on for [`Action::Hardlink`], links can't cross devices.
+    /// Only supported on Unix, elsewhere every file counts as on the same device.
+    pub same_device: bool,
+    /// Directories whose files are compared against but never acted upon, not even to preserve their mtime.
+    /// A duplicate of one of their files is always acted upon in its place, whatever [`Options::keep`] says,
+    /// and copies within them are left alone. Their files still have to be among the paths scanned.
+    pub references: Vec<PathBuf>,
+    /// Directories that always keep at least one copy of every file found in them: a duplicate under one of them
+    /// that's the last copy left there is kept in place of the other copy, whatever [`Options::keep`] says,
+    /// or left alone along with it if that's a reference or the last copy in another of them.
+    /// Unlike references, copies within one of them are still acted upon, down to one.
+    pub protect: Vec<PathBuf>,
+    /// Only ever act on duplicates under these directories, empty to act anywhere.
+    /// Files elsewhere are treated as [`Options::references`]: compared against and kept,
+    /// with a copy under one of these acted upon in their place, and copies of each other logged and left alone.
+    pub delete_under: Vec<PathBuf>,
+    /// Before removing a duplicate that's older than the kept copy, set the kept copy's mtime to the duplicate's.
+    pub preserve_mtime: bool,
+    /// Roots under which directories left empty by deleting or trashing duplicates are removed too, once everything was scanned,
+    /// deepest first, so parents that held nothing else go as well. The roots themselves are kept,
+    /// as is any directory that wasn't emptied by this run; nothing is pruned with [`Options::dry_run`]. Empty for no pruning.
+    pub prune_empty_dirs: Vec<PathBuf>,
+    /// Only act on files with at least this many identical copies, counting the kept one and any [`Options::protect`] leaves in place.
+    /// Above 2 every group is collected before anything is acted upon; 2 or less is the default, acting on every duplicate as soon as it's found.
+    pub min_copies: usize,
+    /// Act on at most this many duplicates, later ones are reported but left in place. Scanning goes on regardless.
+    pub limit: usize,
+    /// Collect a [`Record`] for every duplicate into [`Summary::records`].
+    pub records: bool,
+    /// Collect every set of identical files into [`Summary::groups`].
+    pub groups: bool,
+    /// Collect the [`Options::digest`] of every kept file into [`Summary::hashes`].
+    /// Reused from the scan where it had to hash the file anyway, files that didn't share an ID with another of their size are read once more at the end.
+    pub hashes: bool,
+    /// Called with the number of files scanned so far, starting at 0, and the total if [`Options::count_first`] is set.
+    pub progress: Option<fn(usize, Option<usize>)>,
+    /// Minimum time between progress calls; the first and final counts are always reported.
+    pub progress_interval: Duration,
+    /// Walk and filter every path before scanning, so progress can report a total.
+    /// Holds every path in memory at once, and scanning only starts once the walk is done.
+    pub count_first: bool,
+    /// Before reading a file in full to compare it, check a bloom filter of the fingerprints of every file kept so far:
+    /// a file whose fingerprint was never added can't be a duplicate, so neither it nor the files it shares an ID with are read.
+    /// Fingerprints cover the size and the last page, which costs every file one more small read; worth it on mostly unique files
+    /// that start alike, e.g. logs or images with the same header. Takes 1 MiB, and only ever rules matches out.
+    pub bloom: bool,
+    /// Bytes at the start of each file hashed into its [`ID`], which decides the bucket it's compared within.
+    /// More tells apart files that share long headers without reading them in full, at the cost of reading more of every file.
+    /// A prefix past the size of most files reads them whole, which is logged as a warning.
+    pub prefix_bytes: NonZeroU64,
+    /// Time the phases of the run into [`Summary::profile`].
+    pub profile: bool,
+    /// Scan paths in this order rather than as they come, so with [`Keep::First`] the same copy is kept on every run.
+    /// The other policies only fall back to the order for ties; either way the keeper doesn't depend on thread timing.
+    /// Like [`Options::count_first`] this holds every path in memory at once, and scanning only starts once the walk is done.
+    pub sort: Option<Sort>,
+    /// Read the size of every path first, then scan them in batches of whole size classes, emptying the map of unique files between batches:
+    /// files of different sizes can't be identical, so peak memory is bounded by the largest batch rather than the whole tree.
+    /// With [`Options::compare_bytes`] sizes are only told apart up to that many bytes, so every longer file is in one class.
+    /// Like [`Options::count_first`] this still holds every path in memory at once, and scanning only starts once their sizes are known.
+    pub shard_by_size: bool,
+    /// Once set, workers finish the file they're on and no further paths are scanned.
+    pub stop: Option<&'static AtomicBool>,
+    /// Asked before acting on each duplicate with the action, the duplicate and the original it matched.
+    /// Not asked in dry runs. Setting this forces a single thread, so prompts don't interleave.
+    pub confirm: Option<fn(Action, &Path, &Path) -> Confirm>,
+    /// Called with the action, the duplicate and the original it matched right before acting, e.g. to keep an undo log.
+    /// Not called in dry runs. An error is counted and the duplicate is left alone.
+    pub on_action: Option<OnAction>,
+    /// Called with each duplicate and the original it matched in dry runs, in place of acting on it.
+    pub on_dry_run: Option<fn(&Path, &Path)>,
+    /// Called with the [`Record`] of each duplicate once it was acted upon, or would have been in a dry run,
+    /// from the worker that found it, e.g. to stream results as they come.
+    pub on_record: Option<fn(&Record)>,
+    /// Called with every failure counted in [`Summary::errors`] besides logging it, from the worker that hit it.
+    pub on_failure: Option<fn(&Path, &Error)>,
+    /// Called with a file and each file that shares its ID but turned out to differ, with where they do.
+    /// For debugging why files weren't matched: finding the first difference takes another read of both.
+    pub explain: Option<fn(&Path, &Path, &Mismatch)>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            action: Action::Delete,
+            quarantine: None,
+            keep: Keep::First,
+            keep_path: None,
+            threads: std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN),
+            io_concurrency: None,
+            max_open_files: None,
+            retry: Retry::default(),
+            digest: Digest::Blake3,
+            byte_compare: true,
+            compare_bytes: None,
+            verify_pass: false,
+            skip_open_files: false,
+            on_error: OnError::Skip,
+            exclude: GlobSet::empty(),
+            extensions: Vec::new(),
+            min_size: 0,
+            max_size: u64::MAX,
+            modified_after: None,
+            modified_before: None,
+            empty: Empty::Skip,
+            follow_symlinks: false,
+            include_hidden: false,
+            per_dir: false,
+            by_name: false,
+            same_ext_only: false,
+            same_device: false,
+            references: Vec::new(),
+            protect: Vec::new(),
+            delete_under: Vec::new(),
+            preserve_mtime: false,
+            prune_empty_dirs: Vec::new(),
+            min_copies: 2,
+            limit: usize::MAX,
+            records: false,
+            groups: false,
+            hashes: false,
+            progress: None,
+            progress_interval: Duration::from_millis(50),
+            count_first: false,
+            bloom: false,
+            prefix_bytes: ID_PREFIX,
+            profile: false,
+            sort: None,
+            shard_by_size: false,
+            stop: None,
+            confirm: None,
+            on_action: None,
+            on_dry_run: None,
+            on_record: None,
+            on_failure: None,
+            explain: None,
+        }
+    }
+}
+
+/// How two files sharing an ID differ, see [`Options::explain`].
+#[derive(Debug)]
+pub enum Mismatch {
+    /// Each file's size, first file first.
+    Size(u64, u64),
+    /// The first differing byte, and each file's bytes around it from `start` on.
+    /// The shorter one may end at `offset`, if it's a prefix of the other.
+    Bytes {
+        offset: u64,
+        start: u64,
+        a: Vec<u8>,
+        b: Vec<u8>,
+    },
+}
+
+/// What failed and on which path, inside the [`Error`]s this crate returns for a path; see [`PathError::of`].
+/// The wrapping error keeps the kind of `source`, so matching on [`Error::kind`] works as before.
+#[derive(Debug)]
+pub struct PathError {
+    /// What was being done, e.g. "opening"; `None` where the run had failed on a file in any of several ways.
+    pub op: Option<&'static str>,
+    pub path: PathBuf,
+    pub source: Error,
+}
+
+impl PathError {
+    /// `source`, with what failed on `path`.
+    pub fn wrap(op: &'static str, path: &Path, source: Error) -> Error {
+        Self::boxed(Some(op), path, source)
+    }
+
+    fn boxed(op: Option<&'static str>, path: &Path, source: Error) -> Error {
+        let kind = source.kind();
+        let path = path.to_owned();
+        Error::new(kind, PathError { op, path, source })
+    }
+
+    /// The context of `err`, if it came with one.
+    pub fn of(err: &Error) -> Option<&PathError> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl std::fmt::Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(op) = self.op {
+            write!(f, "{op} ")?;
+        }
+        write!(f, "{}: {}", self.path.display(), self.source)
+    }
+}
+
+impl std::error::Error for PathError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// A duplicate that was acted upon.
+#[derive(Debug)]
+pub struct Record {
+    pub path: PathBuf,
+    /// The copy that was kept.
+    pub original: PathBuf,
+    pub size: u64,
+    pub id: ID,
+}
+
+/// Totals of a run.
+#[derive(Debug)]
+pub struct Summary {
+    pub scanned: usize,
+    /// Duplicates detected, whether they were acted upon or not; with [`Options::min_copies`] only those in groups large enough.
+    pub found: usize,
+    /// Paths skipped for naming a file that was scanned already; not included in `scanned`.
+    pub collapsed: usize,
+    /// Duplicates left in place as either copy changed size or mtime between being scanned and compared.
+    pub changed: usize,
+    /// Copies left in place as they only matched files with another extension, see [`Options::same_ext_only`].
+    pub other_ext: usize,
+    /// Files whose [`ID`] prefix was all zero, e.g. sparse or preallocated ones, so their size went into the ID too.
+    pub zero_prefix: usize,
+    /// Files [`Options::bloom`] showed to be unique without reading them in full.
+    pub ruled_out: usize,
+    /// Only set with [`Options::profile`].
+    pub profile: Option<Profile>,
+    /// Duplicates acted upon, whatever the action.
+    pub deleted: usize,
+    pub bytes_freed: u64,
+    /// Total size of every file scanned.
+    pub bytes_scanned: u64,
+    /// Failed reads and actions.
+    pub errors: usize,
+    /// Empty files scanned, whatever [`Options::empty`] did with them.
+    pub empty: usize,
+    /// Empty unless [`Options::empty`] is [`Empty::Report`].
+    pub empty_files: Vec<PathBuf>,
+    /// Empty unless [`Options::records`] is set.
+    pub records: Vec<Record>,
+    /// Empty unless [`Options::groups`] is set.
+    pub groups: Vec<Group>,
+    /// Empty unless [`Options::hashes`] is set.
+    pub hashes: Vec<Hashed>,
+    /// What was acted upon, by the duplicate's lowercased extension; files without one are under "".
+    pub extensions: BTreeMap<String, ExtensionStats>,
+    /// Directories removed by [`Options::prune_empty_dirs`].
+    pub pruned_dirs: usize,
+}
+
+/// A kept file and the digest of its contents, or with [`Options::compare_bytes`] of the part compared.
+#[derive(Debug)]
+pub struct Hashed {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: [u8; 32],
+    /// Copies acted upon, which had the same contents; empty unless [`Options::groups`] is set.
+    pub duplicates: Vec<PathBuf>,
+}
+
+/// Where the time of a run went, see [`Options::profile`].
+/// Phases are summed over every worker thread, so with several they can add up to more than `total`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Profile {
+    /// Finding the paths to scan, and filtering them.
+    pub walk: Duration,
+    /// Reading prefixes for IDs, and fingerprints with [`Options::bloom`].
+    pub ids: Duration,
+    /// Reading whole files to hash and compare them.
+    pub compare: Duration,
+    /// Acting on duplicates.
+    pub act: Duration,
+    /// Wall-clock time of the whole run.
+    pub total: Duration,
+    /// Bytes read for IDs and fingerprints.
+    pub id_bytes: u64,
+    /// Bytes read to hash and compare files, including re-verifying before acting.
+    pub compare_bytes: u64,
+    /// Read calls making up both.
+    pub reads: u64,
+}
+
+/// [`Profile`] phases being timed, in nanoseconds.
+#[derive(Default)]
+struct Timings {
+    walk: AtomicU64,
+    ids: AtomicU64,
+    compare: AtomicU64,
+    act: AtomicU64,
+    id_bytes: AtomicU64,
+    compare_bytes: AtomicU64,
+    reads: AtomicU64,
+}
+
+/// Duplicates with one extension that were acted upon.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ExtensionStats {
+    pub deleted: usize,
+    pub bytes_freed: u64,
+}
+
+/// A set of identical files.
+#[derive(Debug)]
+pub struct Group {
+    pub id: ID,
+    pub size: u64,
+    /// The copy chosen by [`Options::keep`].
+    pub keeper: PathBuf,
+    /// Every other copy, in the order they were acted upon.
+    pub duplicates: Vec<PathBuf>,
+}
+
+/// Counters and flags shared by the workers; Relaxed is enough, joining the workers synchronizes the final read.
+#[derive(Default)]
+struct Counters {
+    scanned: AtomicUsize,
+    found: AtomicUsize,
+    deleted: AtomicUsize,
+    /// Directories duplicates were removed from, for [`Options::prune_empty_dirs`].
+    emptied: Mutex<std::collections::HashSet<PathBuf>>,
+    pruned_dirs: AtomicUsize,
+    /// Slots taken towards [`Options::limit`]: acted upon plus in progress.
+    reserved: AtomicUsize,
+    bytes_freed: AtomicU64,
+    bytes_scanned: AtomicU64,
+    errors: AtomicUsize,
+    empty: AtomicUsize,
+    empty_files: Mutex<Vec<PathBuf>>,
+    records: Mutex<Vec<Record>>,
+    groups: Mutex<Vec<Group>>,
+    hashes: Mutex<Vec<Hashed>>,
+    extensions: Mutex<BTreeMap<String, ExtensionStats>>,
+    /// Set to stop handing out paths.
+    stop: AtomicBool,
+    /// Set once [`Confirm::All`] was answered.
+    confirmed_all: AtomicBool,
+    /// Set for [`OnError::Abort`].
+    abort: bool,
+    on_failure: Option<fn(&Path, &Error)>,
+    /// Every path handed out so far, see [`Counters::first_visit`].
+    seen: Mutex<std::collections::HashSet<PathBuf>>,
+    collapsed: AtomicUsize,
+    changed: AtomicUsize,
+    other_ext: AtomicUsize,
+    zero_prefix: AtomicUsize,
+    ruled_out: AtomicUsize,
+    /// Files no longer than [`Options::prefix_bytes`], so read whole for their ID.
+    within_prefix: AtomicUsize,
+    bloom: Option<bloom::Bloom>,
+    /// Only set with [`Options::profile`].
+    timings: Option<Timings>,
+    first_error: Mutex<Option<Error>>,
+    io: IoLimits,
+    /// [`Options::references`], canonicalized where possible.
+    references: Vec<PathBuf>,
+    /// [`Options::protect`], canonicalized where possible.
+    protect: Vec<PathBuf>,
+    /// [`Options::delete_under`], canonicalized where possible.
+    delete_under: Vec<PathBuf>,
+    /// [`Options::quarantine`], canonicalized where possible.
+    quarantine: Option<PathBuf>,
+}
+
+impl Counters {
+    /// Reports a failed operation on `path`, or with [`OnError::Abort`] keeps the first one to return and stops the run.
+    fn error(&self, path: &Path, err: &Error) {
+        self.errors.fetch_add(1, Relaxed);
+        if let Some(on_failure) = self.on_failure {
+            on_failure(path, err);
+        }
+        if !self.abort {
+            return log::error!("{}: {err}", path.display());
+        }
+        let mut first = lock(&self.first_error);
+        if first.is_none() {
+            *first = Some(PathError::boxed(
+                None,
+                path,
+                Error::new(err.kind(), err.to_string()),
+            ));
+        } else {
+            log::error!("{}: {err}", path.display()); // raced the first one, still worth knowing
+        }
+        self.stop.store(true, Relaxed);
+    }
+
+    /// Whether `path` wasn't handed out before under any name, e.g. from overlapping roots or a list naming it twice.
+    /// Only the parent is canonicalized, so a symlink isn't mistaken for the file it points to.
+    /// Paths that can't be canonicalized count as new, scanning them reports the error.
+    fn first_visit(&self, path: &Path) -> bool {
+        match canonical(path) {
+            Ok(canonical) => lock(&self.seen).insert(canonical),
+            Err(_) => true,
+        }
+    }
+
+    /// Lets `path` be handed out again, e.g. once a duplicate there was removed, so a file recreated under its name is scanned.
+    fn forget(&self, path: &Path) {
+        if let Ok(canonical) = canonical(path) {
+            lock(&self.seen).remove(&canonical);
+        }
+    }
+
+    /// Whether `path` is under one of [`Options::references`], under its own name or, canonicalized, any other.
+    /// Outside every one of [`Options::delete_under`] counts as under a reference too.
+    fn is_reference(&self, path: &Path) -> bool {
+        if self.references.is_empty() && self.delete_under.is_empty() {
+            return false;
+        }
+        let canonical = canonical(path).ok();
+        let under = |roots: &[PathBuf]| {
+            roots.iter().any(|root| {
+                path.starts_with(root)
+                    || canonical
+                        .as_ref()
+                        .is_some_and(|path| path.starts_with(root))
+            })
+        };
+        under(&self.references) || (!self.delete_under.is_empty() && !under(&self.delete_under))
+    }
+
+    fn quarantined(&self, path: &Path) -> bool {
+        quarantined(self.quarantine.as_deref(), path)
+    }
+
+    /// Indices into [`Options::protect`] of the roots `path` is under, under its own name or, canonicalized, any other.
+    fn protected_roots(&self, path: &Path) -> Vec<usize> {
+        if self.protect.is_empty() {
+            return Vec::new();
+        }
+        let canonical = canonical(path).ok();
+        let under = |root: &PathBuf| {
+            path.starts_with(root)
+                || canonical
+                    .as_ref()
+                    .is_some_and(|path| path.starts_with(root))
+        };
+        (0..self.protect.len())
+            .filter(|&i| under(&self.protect[i]))
+            .collect()
+    }
+
+    /// When a phase started, if it's being timed for [`Options::profile`].
+    fn started(&self) -> Option<Instant> {
+        self.timings.as_ref().map(|_| Instant::now())
+    }
+
+    /// Adds the time since `start` to `phase`, see [`Counters::started`].
+    fn record(&self, phase: fn(&Timings) -> &AtomicU64, start: Option<Instant>) {
+        if let (Some(timings), Some(start)) = (&self.timings, start) {
+            let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+            phase(timings).fetch_add(nanos, Relaxed);
+        }
+    }
+
+    /// `io`, with its reads counted towards `bytes` if they're being profiled.
+    fn tallied<'a>(&'a self, io: Io<'a>, bytes: fn(&Timings) -> &AtomicU64) -> Io<'a> {
+        let tally = (self.timings.as_ref()).map(|timings| (bytes(timings), &timings.reads));
+        Io { tally, ..io }
+    }
+
+    /// Whether the run was quit from a prompt or stopped by the caller.
+    fn stopped(&self, options: &Options) -> bool {
+        self.stop.load(Relaxed) || options.stop.is_some_and(|stop| stop.load(Relaxed))
+    }
+
+    fn summary(self) -> Summary {
+        Summary {
+            scanned: self.scanned.into_inner(),
+            found: self.found.into_inner(),
+            collapsed: self.collapsed.into_inner(),
+            changed: self.changed.into_inner(),
+            other_ext: self.other_ext.into_inner(),
+            zero_prefix: self.zero_prefix.into_inner(),
+            ruled_out: self.ruled_out.into_inner(),
+            profile: self.timings.map(|timings| {
+                let time = |phase: AtomicU64| Duration::from_nanos(phase.into_inner());
+                Profile {
+                    walk: time(timings.walk),
+                    ids: time(timings.ids),
+                    compare: time(timings.compare),
+                    act: time(timings.act),
+                    total: Duration::ZERO, // timed by the caller
+                    id_bytes: timings.id_bytes.into_inner(),
+                    compare_bytes: timings.compare_bytes.into_inner(),
+                    reads: timings.reads.into_inner(),
+                }
+            }),
+            deleted: self.deleted.into_inner(),
+            pruned_dirs: self.pruned_dirs.into_inner(),
+            bytes_freed: self.bytes_freed.into_inner(),
+            bytes_scanned: self.bytes_scanned.into_inner(),
+            errors: self.errors.into_inner(),
+            empty: self.empty.into_inner(),
+            empty_files: self
+                .empty_files
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner),
+            records: self
+                .records
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner),
+            groups: self
+                .groups
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner),
+            hashes: self
+                .hashes
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner),
+            extensions: self
+                .extensions
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+}
+
+/// Whether `path` is in `dir`, a canonicalized [`Options::quarantine`], under its own name or, canonicalized, any other:
+/// moved there by an earlier run, or this one, so never scanned again.
+fn quarantined(dir: Option<&Path>, path: &Path) -> bool {
+    dir.is_some_and(|dir| {
+        path.starts_with(dir) || canonical(path).is_ok_and(|path| path.starts_with(dir))
+    })
+}
+
+/// `path` with its parent canonicalized, so a symlink isn't mistaken for the file it points to.
+fn canonical(path: &Path) -> Result<PathBuf, Error> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            std::fs::canonicalize(parent).map(|parent| parent.join(name))
+        }
+        _ => std::fs::canonicalize(path),
+    }
+}
+
+macro_rules! retry_interrupts {
+    ($e:expr) => {
+        loop {
+            match $e {
+                Ok(x) => break Ok(x),
+                Err(err) => match err.kind() {
+                    ErrorKind::Interrupted => continue, // Try $e again, if error == interrupted
+                    _ => break Err(err),
+                },
+            }
+        }
+    };
+}
+
+/// Like [`retry_interrupts`], also retrying the errors an [`Io`] says to.
+macro_rules! retry_transient {
+    ($io:expr, $e:expr) => {{
+        let io: Io<'_> = $io;
+        let mut attempt = 0;
+        loop {
+            match $e {
+                Ok(x) => break Ok(x),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if attempt < io.retry.attempts && io.retries(&err) => {
+                    std::thread::sleep(io.retry.delay.saturating_mul(1 << attempt.min(16)));
+                    attempt += 1;
+                }
+                Err(err) => break Err(err),
+            }
+        }
+    }};
+}
+
+mod bloom; // after the macros, which it uses
+
+/// Removes all but one copy of every set of identical files among `paths`.
+pub fn deduplicate(
+    paths: impl Iterator<Item = PathBuf> + Send,
+    options: &Options,
+) -> Result<Summary, Error> {
+    let paths = paths.filter(|path| is_candidate(path, options));
+    if options.count_first || options.sort.is_some() || options.shard_by_size {
+        let start = Instant::now();
+        let mut paths: Vec<_> = paths.collect();
+        if let Some(sort) = options.sort {
+            sort.sort(&mut paths);
+        }
+        let total = options.count_first.then_some(paths.len());
+        let batches = if options.shard_by_size {
+            size_batches(paths, options)
+        } else {
+            vec![paths.into_iter().enumerate().map(unhashed).collect()]
+        };
+        let walked = start.elapsed();
+        let mut summary = run(batches.into_iter().map(Vec::into_iter), total, options)?;
+        if let Some(profile) = &mut summary.profile {
+            profile.walk += walked;
+            profile.total += walked;
+        }
+        Ok(summary)
+    } else {
+        run(
+            std::iter::once(paths.enumerate().map(unhashed)),
+            None,
+            options,
+        )
+    }
+}
+
+/// Like [`deduplicate`], for files whose [`Options::digest`] of their whole contents is known already, e.g. from an index kept elsewhere.
+/// Their IDs come from those hashes, so no file is read to tell them apart, and with [`Options::byte_compare`] only actual duplicates
+/// and their kept copies are read, to confirm them. Hashes are trusted otherwise: a file that changed since it was hashed may be taken for
+/// a copy of what it used to be. Files are scanned in the order given, [`Options::sort`], [`Options::count_first`],
+/// [`Options::shard_by_size`], [`Options::compare_bytes`] and [`Options::bloom`] don't apply.
+pub fn deduplicate_hashed(
+    files: impl Iterator<Item = ([u8; 32], PathBuf)> + Send,
+    options: &Options,
+) -> Result<Summary, Error> {
+    let files = files
+        .filter(|(_, path)| is_candidate(path, options))
+        .enumerate()
+        .map(|(order, (hash, path))| (order, path, Some(hash)));
+    let options = Options {
+        compare_bytes: None,
+        bloom: false,
+        ..options.clone()
+    };
+    run(std::iter::once(files), None, &options)
+}
+
+/// Like [`deduplicate`], then goes on with `arrivals` as they come, e.g. files appearing under a watched directory, until they run out.
+/// Unique files are kept throughout, so each arrival is compared against everything scanned before it.
+/// Arrivals found outside of `paths` aren't filtered as a [`Walk`] would, see [`Walk::admits`] for that.
+/// Paths are scanned as they're handed out, so [`Options::sort`], [`Options::count_first`] and [`Options::shard_by_size`] don't apply,
+/// and with [`Options::min_copies`] above 2 nothing is acted upon until `arrivals` end.
+/// A path is scanned again only once a duplicate there was acted upon or it changed while being compared,
+/// so a duplicate recreated under its old name goes too; otherwise a file that changes after it was scanned keeps the ID it had.
+pub fn watch(
+    paths: impl Iterator<Item = PathBuf> + Send,
+    arrivals: impl Iterator<Item = PathBuf> + Send,
+    options: &Options,
+) -> Result<Summary, Error> {
+    let paths = paths
+        .chain(arrivals)
+        .filter(|path| is_candidate(path, options));
+    run(
+        std::iter::once(paths.enumerate().map(unhashed)),
+        None,
+        options,
+    )
+}
+
+/// A path to scan at `order`, to be hashed if needed.
+fn unhashed((order, path): (usize, PathBuf)) -> Entry {
+    (order, path, None)
+}
+
+/// Hashes every file among `paths` in full with [`Options::digest`], without comparing or acting on any of them.
+/// `paths` are filtered as for [`deduplicate`]; the result has [`Summary::hashes`] sorted by path,
+/// and only [`Summary::scanned`], [`Summary::bytes_scanned`] and [`Summary::errors`] besides.
+/// Unlike a scan, empty files are hashed too: their hash is as much a fingerprint as any.
+pub fn hash_files(
+    paths: impl Iterator<Item = PathBuf> + Send,
+    options: &Options,
+) -> Result<Summary, Error> {
+    let counters = Counters {
+        abort: options.on_error == OnError::Abort,
+        on_failure: options.on_failure,
+        io: IoLimits::new(options.io_concurrency),
+        quarantine: options
+            .quarantine
+            .as_ref()
+            .map(|dir| std::fs::canonicalize(dir).unwrap_or_else(|_| dir.clone())),
+        ..Default::default()
+    };
+    let queue = Mutex::new(paths.filter(|path| is_candidate(path, options)));
+    let hashes = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..options.threads.get() {
+            scope.spawn(|| loop {
+                if counters.stopped(options) {
+                    break;
+                }
+                let Some(path) = lock(&queue).next() else {
+                    break;
+                };
+                if counters.quarantined(&path) {
+                    continue;
+                }
+                if !counters.first_visit(&path) {
+                    counters.collapsed.fetch_add(1, Relaxed);
+                    continue;
+                }
+                let metadata = retry_transient!(Io::new(None, options), std::fs::metadata(&path));
+                let metadata = match metadata {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        counters.error(&path, &err);
+                        continue;
+                    }
+                };
+                let gate = counters.io.gate(Inode::of(&metadata).dev);
+                let io = Io::new(gate.as_deref(), options);
+                match read_hash(&path, options.digest, u64::MAX, io) {
+                    Ok(hash) => {
+                        counters.scanned.fetch_add(1, Relaxed);
+                        counters.bytes_scanned.fetch_add(metadata.len(), Relaxed);
+                        lock(&hashes).push(Hashed {
+                            path,
+                            size: metadata.len(),
+                            hash,
+                            duplicates: Vec::new(),
+                        });
+                    }
+                    Err(err) => counters.error(&path, &err),
+                }
+            });
+        }
+    });
+
+    if let Some(err) = lock(&counters.first_error).take() {
+        return Err(err);
+    }
+    let mut hashes = hashes.into_inner().unwrap_or_else(PoisonError::into_inner);
+    hashes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(Summary {
+        hashes,
+        ..counters.summary()
+    })
+}
+
+/// Scans each of `batches` in turn, see [`Options::shard_by_size`]; paths come with their place among all of them.
+fn run<B: Iterator<Item = Entry> + Send>(
+    batches: impl Iterator<Item = B>,
+    total: Option<usize>,
+    options: &Options,
+) -> Result<Summary, Error> {
+    let start = Instant::now();
+    let mut batches = batches.peekable();
+    let map = Mutex::new(batches.peek().map_or_else(Default::default, map_from_iter));
+    let setup = start.elapsed();
+    let mut summary = remove_duplicates(batches, total, &map, options)?;
+    if let Some(profile) = &mut summary.profile {
+        profile.walk += setup; // sizing the map from the paths' hint
+        profile.total = start.elapsed();
+    }
+    Ok(summary)
+}
+
+fn remove_duplicates<B: Iterator<Item = Entry> + Send>(
+    batches: impl Iterator<Item = B>,
+    total: Option<usize>,
+    map: &Map,
+    options: &Options,
+) -> Result<Summary, Error> {
+    let progress = options.progress.unwrap_or(|_, _| {});
+    progress(0, total); // reports 0 for when no files were found
+
+    let counters = Counters {
+        abort: options.on_error == OnError::Abort,
+        on_failure: options.on_failure,
+        io: IoLimits::new(options.io_concurrency),
+        references: options
+            .references
+            .iter()
+            .map(|root| std::fs::canonicalize(root).unwrap_or_else(|_| root.clone()))
+            .collect(),
+        protect: options
+            .protect
+            .iter()
+            .map(|root| std::fs::canonicalize(root).unwrap_or_else(|_| root.clone()))
+            .collect(),
+        delete_under: options
+            .delete_under
+            .iter()
+            .map(|root| std::fs::canonicalize(root).unwrap_or_else(|_| root.clone()))
+            .collect(),
+        quarantine: options
+            .quarantine
+            .as_ref()
+            .map(|dir| std::fs::canonicalize(dir).unwrap_or_else(|_| dir.clone())),
+        bloom: options.bloom.then(bloom::Bloom::new),
+        timings: options.profile.then(Timings::default),
+        ..Default::default()
+    };
+    let threads = match options.confirm {
+        Some(_) => 1,
+        None => options.threads.get(),
+    };
+    let open_files = options
+        .max_open_files
+        .map(|max| Semaphore::new(max.max(NonZeroUsize::new(FILES_PER_SCAN).unwrap())));
+    let reserved = lock(map).capacity();
+    let mut buckets = 0;
+    let last_progress = Mutex::new(Instant::now());
+    for batch in batches {
+        if counters.stopped(options) {
+            break;
+        }
+        // The walker is lazy, so the queue also hands out paths as they're discovered.
+        let queue = Mutex::new(batch);
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    if counters.stopped(options) {
+                        break;
+                    }
+                    let mut queue = lock(&queue);
+                    let start = counters.started();
+                    let next = queue.next();
+                    drop(queue);
+                    counters.record(|t| &t.walk, start);
+                    let Some((order, cur_path, known)) = next else {
+                        break;
+                    };
+                    if counters.quarantined(&cur_path) {
+                        continue;
+                    }
+                    if !counters.first_visit(&cur_path) {
+                        counters.collapsed.fetch_add(1, Relaxed);
+                        continue;
+                    }
+                    let files = open_files
+                        .as_ref()
+                        .map(|files| files.acquire_many(FILES_PER_SCAN));
+                    scan_file(cur_path, order, known, map, options, &counters);
+                    drop(files);
+                    let scanned = counters.scanned.fetch_add(1, Relaxed) + 1;
+                    // Whoever holds the lock is reporting already, no need to wait for it.
+                    if let Ok(mut last) = last_progress.try_lock() {
+                        if last.elapsed() >= options.progress_interval {
+                            *last = Instant::now();
+                            progress(scanned, total);
+                        }
+                    }
+                });
+            }
+        });
+
+        if options.min_copies > 2 {
+            prune(map, options, &counters);
+        }
+        buckets = buckets.max(lock(map).len());
+        drain(map, options, &counters);
+    }
+    if buckets > reserved {
+        log::debug!("{buckets} IDs found, {reserved} were reserved up front");
+    }
+    prune_empty_dirs(options, &counters);
+    if let Some(err) = lock(&counters.first_error).take() {
+        return Err(err);
+    }
+    let within_prefix = counters.within_prefix.load(Relaxed);
+    if options.prefix_bytes > ID_PREFIX && within_prefix * 2 > counters.scanned.load(Relaxed) {
+        log::warn!(
+            "{within_prefix} files are no longer than the {}-byte ID prefix, so each was read whole for its ID; a smaller prefix would read less",
+            options.prefix_bytes
+        );
+    }
+    let summary = counters.summary();
+    progress(summary.scanned, total); // threads may have reported out of order
+    Ok(summary)
+}
+
+/// Depth-first directory walker over one or more roots, visited in order.
+///
+/// Directories are never yielded, there's nothing to compare in them.
+/// Without `recursive` this yields the other entries of the root directories, same as chained `read_dir`s.
+/// With `recursive` directories are descended into.
+/// Symlinked directories are never followed, as `DirEntry::file_type` doesn't traverse symlinks; this avoids cycles.
+/// Unreadable directories and entries are reported on stderr and counted in [`Walk::errors`], the walk carries on.
+pub struct Walk {
+    stack: Vec<Frame>,
+    rules: Rules,
+    threads: NonZeroUsize,
+    /// Paths found by the threads listing directories, once started; see [`Walk::threads`].
+    found: Option<Receiver<PathBuf>>,
+    /// As given, for [`Walk::admits`].
+    roots: Vec<PathBuf>,
+}
+
+/// What [`Walk`] yields, skips and descends into, shared with the threads of a parallel walk.
+#[derive(Clone)]
+struct Rules {
+    recursive: bool,
+    include_hidden: bool,
+    ignore_files: bool,
+    skip_dirs: Vec<PathBuf>,
+    max_depth: Option<usize>,
+    errors: Arc<AtomicUsize>,
+    on_failure: Option<fn(&Path, &Error)>,
+}
+
+/// A directory being read by [`Walk`].
+struct Frame {
+    /// For error messages.
+    dir: PathBuf,
+    /// Opened when the directory is listed rather than when it's found, so a parallel walk doesn't hold
+    /// a file descriptor for every directory it has queued; roots are opened up front.
+    entries: Option<ReadDir>,
+    /// Directories between this one and its root, 0 for roots.
+    depth: usize,
+    /// The [`IGNORE_FILE`]s of this directory and its parents, innermost last.
+    /// Loaded with the first entry for roots, when the directory is entered for the rest.
+    ignores: Option<Arc<Vec<Gitignore>>>,
+}
+
+/// What [`Walk`] does with a directory entry.
+enum Step {
+    Skip,
+    Yield(PathBuf),
+    Enter(Frame),
+}
+
+/// Gitignore-style patterns of files for [`Walk`] to skip, see [`Walk::ignore_files`].
+pub const IGNORE_FILE: &str = ".cleanerignore";
+
+/// Paths a parallel [`Walk`] keeps found ahead of the scan, beyond which its threads wait.
+const WALK_AHEAD: usize = 4096;
+
+impl Walk {
+    /// Opens every root up front, so a bad argument fails before anything is scanned.
+    pub fn new(
+        roots: impl IntoIterator<Item = impl AsRef<Path>>,
+        recursive: bool,
+    ) -> Result<Self, Error> {
+        let mut stack = roots
+            .into_iter()
+            .map(|dir| {
+                let dir = dir.as_ref();
+                if dir.is_file() {
+                    let msg = format!("{}: expected a directory, got a file", dir.display());
+                    return Err(Error::new(ErrorKind::NotADirectory, msg));
+                }
+                retry_interrupts!(read_dir(dir))
+                    .map(|entries| Frame {
+                        dir: dir.to_owned(),
+                        entries: Some(entries),
+                        depth: 0,
+                        ignores: None,
+                    })
+                    .map_err(|err| PathError::wrap("reading directory", dir, err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let roots = stack.iter().map(|frame| frame.dir.clone()).collect();
+        stack.reverse(); // the stack is popped from the back
+        Ok(Self {
+            stack,
+            rules: Rules {
+                recursive,
+                include_hidden: false,
+                ignore_files: false,
+                skip_dirs: Vec::new(),
+                max_depth: None,
+                errors: Arc::default(),
+                on_failure: None,
+            },
+            threads: NonZeroUsize::MIN,
+            found: None,
+            roots,
+        })
+    }
+
+    /// Skip what the [`IGNORE_FILE`] of a root, or with `recursive` of any directory within, matches.
+    /// Patterns are gitignore's, applying from the directory they're in down; ones in nested files take precedence.
+    pub fn ignore_files(mut self, read: bool) -> Self {
+        self.rules.ignore_files = read;
+        self
+    }
+
+    /// Also report every failure to `on_failure`, like [`Options::on_failure`].
+    pub fn on_failure(mut self, on_failure: Option<fn(&Path, &Error)>) -> Self {
+        self.rules.on_failure = on_failure;
+        self
+    }
+
+    /// With `recursive`, never descend into a directory named like one of `dirs`, e.g. `node_modules` or `.git`,
+    /// or under one of them if it's a path, e.g. `/home/me/src/target`; relative paths are taken from the current directory.
+    /// Roots are walked regardless.
+    pub fn skip_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.rules.skip_dirs = dirs
+            .into_iter()
+            .map(|dir| {
+                if is_name(&dir) {
+                    dir
+                } else {
+                    std::path::absolute(&dir).unwrap_or(dir)
+                }
+            })
+            .collect();
+        self
+    }
+
+    /// With `recursive`, never descend more than `depth` directories below a root; 0 only yields the roots' own entries, as without `recursive`.
+    pub fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.rules.max_depth = depth;
+        self
+    }
+
+    /// Descend into hidden directories too, see [`Options::include_hidden`].
+    pub fn include_hidden(mut self, include: bool) -> Self {
+        self.rules.include_hidden = include;
+        self
+    }
+
+    /// List this many directories at once, on threads of their own, handing out paths as they're found.
+    /// Faster on trees of many directories, especially over the network, but paths no longer come in any set order:
+    /// a later root's files may come before an earlier one's, which matters to [`Keep::First`] unless [`Options::sort`] is set.
+    pub fn threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Whether the walk would yield a file, for files under its roots found some other way, e.g. by watching them:
+    /// the directories between a root and the file are checked as the walk checks those it descends into,
+    /// against hidden and skipped directories, the depth limit and [`IGNORE_FILE`]s, and then the file against those.
+    /// The filter outlives the walk, so it also applies to files appearing after it.
+    pub fn admits(&self) -> impl Fn(&Path) -> bool + Send + 'static {
+        let (rules, roots) = (self.rules.clone(), self.roots.clone());
+        move |path| rules.admits(&roots, path)
+    }
+
+    /// Number of directories and entries that couldn't be read, shared so it can be checked after the walk is consumed.
+    pub fn errors(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.rules.errors)
+    }
+
+    /// Hands the roots to [`Walk::threads`] threads, which list them and everything under them into `found`.
+    /// They stop once everything was listed, or once the walk is dropped.
+    fn start(&mut self) -> &Receiver<PathBuf> {
+        let (sender, found) = std::sync::mpsc::sync_channel(WALK_AHEAD);
+        let queue = Arc::new(DirQueue {
+            pending: Mutex::new((std::mem::take(&mut self.stack), 0)),
+            changed: Condvar::new(),
+        });
+        for _ in 0..self.threads.get() {
+            let (rules, queue, sender) = (self.rules.clone(), Arc::clone(&queue), sender.clone());
+            std::thread::spawn(move || rules.list(&queue, &sender));
+        }
+        self.found.insert(found)
+    }
+}
+
+impl Rules {
+    /// Whether `dir` is one of [`Walk::skip_dirs`]; paths are compared as the walk found them, then made absolute.
+    fn skipped(&self, dir: &Path) -> bool {
+        let mut absolute = None;
+        self.skip_dirs.iter().any(|skip| {
+            if is_name(skip) {
+                return dir.file_name() == Some(skip.as_os_str());
+            }
+            dir.starts_with(skip)
+                || absolute
+                    .get_or_insert_with(|| std::path::absolute(dir).ok())
+                    .as_ref()
+                    .is_some_and(|dir| dir.starts_with(skip))
+        })
+    }
+
+    /// `ignores` with the [`IGNORE_FILE`] in `dir` added, if there is one.
+    fn load_ignores(
+        &self,
+        dir: &Path,
+        ignores: Option<&Arc<Vec<Gitignore>>>,
+    ) -> Arc<Vec<Gitignore>> {
+        let ignores = ignores.cloned().unwrap_or_default();
+        let path = dir.join(IGNORE_FILE);
+        if !self.ignore_files || !path.is_file() {
+            return ignores;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(err) = builder.add(&path) {
+            self.error(&path, &Error::other(err)); // the patterns that did parse still apply
+        }
+        match builder.build() {
+            Ok(ignore) => {
+                let mut ignores = Vec::clone(&ignores);
+                ignores.push(ignore);
+                Arc::new(ignores)
+            }
+            Err(err) => {
+                self.error(&path, &Error::other(err));
+                ignores
+            }
+        }
+    }
+
+    fn error(&self, path: &Path, err: &Error) {
+        log::error!("{}: {err}", path.display());
+        self.errors.fetch_add(1, Relaxed);
+        if let Some(on_failure) = self.on_failure {
+            on_failure(path, err);
+        }
+    }
+
+    /// See [`Walk::admits`]; `path` is taken to be a file.
+    fn admits(&self, roots: &[PathBuf], path: &Path) -> bool {
+        let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+        let under = roots.iter().find_map(|root| match path.strip_prefix(root) {
+            Ok(relative) => Some((root.clone(), relative.to_owned())),
+            // Watchers may report paths in another form than the roots were given in.
+            Err(_) => {
+                let root = absolute(root);
+                let relative = absolute(path).strip_prefix(&root).ok()?.to_owned();
+                Some((root, relative))
+            }
+        });
+        let Some((mut dir, relative)) = under else {
+            return false;
+        };
+        let mut names: Vec<_> = relative.iter().collect();
+        let Some(file) = names.pop() else {
+            return false; // a root itself
+        };
+        if !names.is_empty() && !self.recursive
+            || self.max_depth.is_some_and(|max| names.len() > max)
+        {
+            return false;
+        }
+        let mut ignores = self.load_ignores(&dir, None);
+        for name in names {
+            dir.push(name);
+            if is_ignored(Some(&ignores), &dir, true)
+                || self.skipped(&dir)
+                || (!self.include_hidden && is_hidden(&dir))
+            {
+                return false;
+            }
+            ignores = self.load_ignores(&dir, Some(&ignores));
+        }
+        !is_ignored(Some(&ignores), &dir.join(file), false)
+    }
+
+    /// Whether to yield `entry`, of a directory with `ignores` at `depth`, skip it, or descend into it.
+    fn step(
+        &self,
+        entry: std::fs::DirEntry,
+        ignores: Option<&Arc<Vec<Gitignore>>>,
+        depth: usize,
+    ) -> Step {
+        let path = entry.path();
+        let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+        if is_ignored(ignores, &path, is_dir) {
+            return Step::Skip;
+        }
+        if !is_dir {
+            return Step::Yield(path);
+        }
+        if self.recursive && !self.skip_dirs.is_empty() && self.skipped(&path) {
+            log::debug!("SKIPPED {}", path.display());
+            return Step::Skip;
+        }
+        if !self.recursive || (!self.include_hidden && is_hidden(&path)) {
+            return Step::Skip;
+        }
+        if self.max_depth.is_some_and(|max| depth >= max) {
+            log::debug!("TOO DEEP {}", path.display());
+            return Step::Skip;
+        }
+        Step::Enter(Frame {
+            ignores: Some(self.load_ignores(&path, ignores)),
+            dir: path,
+            entries: None,
+            depth: depth + 1,
+        })
+    }
+
+    /// Opens `frame` for listing if it isn't yet; an unreadable directory is reported, and `None` returned.
+    fn open<'a>(&self, frame: &'a mut Frame) -> Option<&'a mut ReadDir> {
+        if frame.entries.is_none() {
+            match retry_interrupts!(read_dir(&frame.dir)) {
+                Ok(entries) => frame.entries = Some(entries),
+                Err(err) => self.error(&frame.dir, &err),
+            }
+        }
+        frame.entries.as_mut()
+    }
+
+    /// One thread of a parallel walk: lists directories from `queue` until none are left, sending their files to `found`.
+    fn list(&self, queue: &DirQueue, found: &SyncSender<PathBuf>) {
+        while let Some(mut frame) = queue.take() {
+            if self.open(&mut frame).is_none() {
+                queue.done();
+                continue;
+            }
+            let entries = frame.entries.take().into_iter().flatten();
+            let ignores = match frame.ignores {
+                Some(ignores) => ignores,
+                None => self.load_ignores(&frame.dir, None),
+            };
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        self.error(&frame.dir, &err);
+                        continue;
+                    }
+                };
+                match self.step(entry, Some(&ignores), frame.depth) {
+                    Step::Skip => {}
+                    Step::Yield(path) => {
+                        if found.send(path).is_err() {
+                            return queue.close(); // the walk was dropped, nobody is listening
+                        }
+                    }
+                    Step::Enter(frame) => queue.push(frame),
+                }
+            }
+            queue.done();
+        }
+    }
+}
+
+/// Whether the innermost of `ignores` with a pattern matching `path` ignores it.
+fn is_ignored(ignores: Option<&Arc<Vec<Gitignore>>>, path: &Path, is_dir: bool) -> bool {
+    let matched = ignores
+        .iter()
+        .flat_map(|ignores| ignores.iter().rev())
+        .find_map(|ignore| match ignore.matched(path, is_dir) {
+            ignore::Match::None => None,
+            matched => Some(matched.is_ignore()),
+        });
+    matched == Some(true)
+}
+
+/// Directories left to list in a parallel [`Walk`], and how many are being listed.
+struct DirQueue {
+    pending: Mutex<(Vec<Frame>, usize)>,
+    changed: Condvar,
+}
+
+impl DirQueue {
+    /// The next directory to list, waiting while others are listed and may find more; `None` once all were.
+    fn take(&self) -> Option<Frame> {
+        let mut pending = lock(&self.pending);
+        loop {
+            if let Some(frame) = pending.0.pop() {
+                pending.1 += 1;
+                return Some(frame);
+            }
+            if pending.1 == 0 {
+                return None;
+            }
+            pending = self
+                .changed
+                .wait(pending)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn push(&self, frame: Frame) {
+        lock(&self.pending).0.push(frame);
+        self.changed.notify_one();
+    }
+
+    /// Marks a directory taken as listed.
+    fn done(&self) {
+        let mut pending = lock(&self.pending);
+        pending.1 -= 1;
+        if pending.1 == 0 && pending.0.is_empty() {
+            self.changed.notify_all(); // wake the others, all is listed
+        }
+    }
+
+    /// Drops every directory left, so the other threads stop too.
+    fn close(&self) {
+        lock(&self.pending).0.clear();
+        self.done();
+    }
+}
+
+impl Iterator for Walk {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        if self.threads.get() > 1 {
+            let found = match &self.found {
+                Some(found) => found,
+                None => self.start(),
+            };
+            return found.recv().ok();
+        }
+        loop {
+            let frame = self.stack.last()?;
+            if frame.ignores.is_none() {
+                let ignores = self.rules.load_ignores(&frame.dir, None);
+                self.stack.last_mut()?.ignores = Some(ignores);
+            }
+            let frame = self.stack.last_mut()?;
+            let Some(entries) = self.rules.open(frame) else {
+                self.stack.pop();
+                continue;
+            };
+            let entry = match entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => {
+                    let dir = frame.dir.clone();
+                    self.rules.error(&dir, &err);
+                    continue;
+                }
+                None => {
+                    self.stack.pop(); // directory exhausted, continue with its parent
+                    continue;
+                }
+            };
+            match self.rules.step(entry, frame.ignores.as_ref(), frame.depth) {
+                Step::Skip => {}
+                Step::Yield(path) => return Some(path),
+                Step::Enter(frame) => self.stack.push(frame),
+            }
+        }
+    }
+
+    /// Sums the hints of every directory still being read.
+    /// Nested directories aren't known up front, so recursive walks have no upper bound.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.found.is_some() {
+            return (0, None); // the directories are with the threads
+        }
+        self.stack
+            .iter()
+            .map(|frame| frame.entries.as_ref().map_or((0, None), ReadDir::size_hint))
+            .fold(
+                (0, (!self.rules.recursive).then_some(0)),
+                |(lower, upper), (dir_lower, dir_upper)| {
+                    (
+                        lower.saturating_add(dir_lower),
+                        upper.zip(dir_upper).and_then(|(a, b)| a.checked_add(b)),
+                    )
+                },
+            )
+    }
+}
+
+/// Whether `path` passes the filters in `options`.
+fn is_candidate(path: &Path, options: &Options) -> bool {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.is_symlink()) {
+        if !options.follow_symlinks {
+            log::warn!("skipping symlink {}", path.display());
+            return false;
+        }
+        if std::fs::metadata(path).is_ok_and(|m| m.is_dir()) {
+            return false;
+        }
+    }
+    if !options.include_hidden && is_hidden(path) {
+        return false;
+    }
+    let exclude = &options.exclude;
+    if exclude.is_match(path) || path.file_name().is_some_and(|name| exclude.is_match(name)) {
+        return false;
+    }
+    if !options.extensions.is_empty() {
+        let Some(ext) = path.extension().and_then(|ext| ext.to_str()) else {
+            return false;
+        };
+        if !options
+            .extensions
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(ext))
+        {
+            return false;
+        }
+    }
+    let by_size = options.min_size > 0 || options.max_size < u64::MAX;
+    let by_mtime = options.modified_after.is_some() || options.modified_before.is_some();
+    if by_size || by_mtime {
+        // files whose metadata can't be read are let through, so the error surfaces when scanning them
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return true;
+        };
+        if !(options.min_size..=options.max_size).contains(&metadata.len()) {
+            return false;
+        }
+        if let (true, Ok(mtime)) = (by_mtime, metadata.modified()) {
+            let after = options.modified_after.is_none_or(|after| mtime >= after);
+            let before = options.modified_before.is_none_or(|before| mtime < before);
+            return after && before;
+        }
+    }
+    true
+}
+
+/// Whether `path` is a bare file name, with no directory in it.
+fn is_name(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    )
+}
+
+/// Whether the file name of `path` starts with a dot.
+#[cfg(not(windows))]
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+}
+
+/// Whether the file name of `path` starts with a dot or it has the hidden attribute.
+#[cfg(windows)]
+fn is_hidden(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    path.file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+        || std::fs::symlink_metadata(path)
+            .is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+/// Locks `mutex`, ignoring poisoning; every critical section leaves its data consistent.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Buckets reserved when the paths don't say how many there are, as `read_dir` never does:
+/// without it a large directory has the map reallocate a dozen times before reaching its size.
+const MIN_CAPACITY: usize = 1024;
+
+/// Most buckets reserved up front, however many paths there are; a bogus upper bound mustn't allocate gigabytes.
+const MAX_CAPACITY: usize = 1 << 20;
+
+/// A map sized for `iter`: its upper bound if it has one, as every path may be unique,
+/// otherwise at least [`MIN_CAPACITY`]; past [`MAX_CAPACITY`] it grows as needed.
+fn map_from_iter<K, V>(iter: &impl Iterator) -> HashMap<K, V> {
+    use nohash_hasher::BuildNoHashHasher;
+    use std::collections::HashMap;
+
+    let capacity = match iter.size_hint() {
+        (_, Some(upper)) => upper,
+        (lower, None) => lower.max(MIN_CAPACITY),
+    };
+    HashMap::with_capacity_and_hasher(capacity.min(MAX_CAPACITY), BuildNoHashHasher::default())
+}
+
+/// Hashes the first `prefix` bytes of the file, or `limit` if that's less, with xxh3, and tells whether they were all zero;
+/// equal IDs only mean the files start alike, so matches must still be hashed and compared in full.
+/// There's no minimum length: short files are hashed like any other, and all empty files share one bucket.
+fn read_id(path: &Path, prefix: NonZeroU64, limit: u64, io: Io<'_>) -> Result<(ID, bool), Error> {
+    use std::io::Read;
+
+    let mut file = io.reader(io.open(path)?).take(limit.min(prefix.get()));
+    let zero = |bytes: &[u8]| bytes.iter().all(|&byte| byte == 0);
+    if prefix <= ID_PREFIX {
+        let mut buf = [0u8; ID_PREFIX.get() as usize];
+        let len = read_full(&mut file, &mut buf)?;
+        return Ok((
+            xxhash_rust::xxh3::xxh3_64(&buf[..len]),
+            len > 0 && zero(&buf[..len]),
+        ));
+    }
+    // Streamed, a large prefix mustn't take as much memory per worker; the hash is the same either way.
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    let mut buf = vec![0u8; BUFSIZE];
+    let (mut zeros, mut len) = (true, 0);
+    loop {
+        match read_full(&mut file, &mut buf)? {
+            0 => break,
+            n => {
+                hasher.update(&buf[..n]);
+                zeros &= zero(&buf[..n]);
+                len += n;
+            }
+        }
+    }
+    Ok((hasher.digest(), len > 0 && zeros))
+}
+
+/// Either pushes `cur_path` into its bucket as a new unique file, or acts on it (or the copy it replaces) as a duplicate.
+///
+/// Invariant: a bucket never holds two files with identical contents, however many threads scan at once,
+/// bar copies [`Options::same_ext_only`] leaves in place: those differ in extension from every identical file before them.
+/// Same-extension candidates are compared first and a kept copy is only swapped for one with its extension,
+/// so no two identical files with the same extension share a bucket either way; a hard link to one it holds is skipped, not pushed.
+/// New buckets are created under the map lock, and an existing bucket is locked for the whole compare-then-push,
+/// so every file is compared against all uniques that were pushed before it.
+/// `order` is where `cur_path` came among the paths, see [`Keep::prefers`]; `known` is its hash if it came with one.
+fn scan_file(
+    cur_path: PathBuf,
+    order: usize,
+    known: Option<Hash>,
+    map: &Map,
+    options: &Options,
+    counters: &Counters,
+) {
+    let limit = options.compare_bytes.unwrap_or(u64::MAX);
+    // Read first for the device, which decides how many reads may run alongside this one.
+    let metadata = match retry_transient!(Io::new(None, options), std::fs::metadata(&cur_path)) {
+        Ok(metadata) => metadata,
+        Err(err) => return counters.error(&cur_path, &err),
+    };
+    let (size, inode, modified) = (
+        metadata.len(),
+        Inode::of(&metadata),
+        metadata.modified().ok(),
+    );
+    if size == 0 {
+        counters.empty.fetch_add(1, Relaxed);
+        match options.empty {
+            Empty::Skip => return log::debug!("EMPTY {}", cur_path.display()),
+            Empty::Report => return lock(&counters.empty_files).push(cur_path),
+            Empty::Dedup => {}
+        }
+    }
+    let reference = counters.is_reference(&cur_path);
+    let protected = counters.protected_roots(&cur_path);
+    let cur_gate = counters.io.gate(inode.dev);
+    let cur_io = Io::new(cur_gate.as_deref(), options);
+    let start = counters.started();
+    if size <= options.prefix_bytes.get() && known.is_none() {
+        counters.within_prefix.fetch_add(1, Relaxed);
+    }
+    let id_io = counters.tallied(cur_io, |t| &t.id_bytes);
+    let id = match known {
+        // The hash covers the whole file, so files sharing an ID this way are told apart from the start.
+        Some(hash) => Ok((ID::from_le_bytes(hash[..8].try_into().unwrap()), false)),
+        None => read_id(&cur_path, options.prefix_bytes, limit, id_io),
+    };
+    let mut id = match id {
+        Ok((id, false)) => id,
+        Ok((id, true)) => {
+            // Sparse and preallocated files start with zeros alike, mixing in the size keeps them from all sharing one bucket;
+            // only files of the same size are compared anyway.
+            counters.zero_prefix.fetch_add(1, Relaxed);
+            id ^ xxhash_rust::xxh3::xxh3_64(&size.min(limit).to_le_bytes())
+        }
+        Err(err) => return counters.error(&cur_path, &err),
+    };
+    counters.record(|t| &t.ids, start);
+    counters.bytes_scanned.fetch_add(size, Relaxed);
+    if options.per_dir {
+        // Mixing in the parent keeps directories in separate buckets; a collision here is caught by the parent check below.
+        let parent = cur_path.parent().unwrap_or(Path::new(""));
+        id ^= xxhash_rust::xxh3::xxh3_64(parent.as_os_str().as_encoded_bytes());
+    }
+    let name = options.by_name.then(|| lowercase_name(&cur_path));
+    if let Some(name) = &name {
+        id ^= xxhash_rust::xxh3::xxh3_64(name.as_bytes()); // like per_dir, a collision is caught by the name check below
+    }
+    let same_device = options.same_device || options.action == Action::Hardlink;
+    if same_device {
+        id ^= inode.dev; // like per_dir, a collision is caught by the device check below
+    }
+    let start = counters.started();
+    let fingerprint = match counters.bloom {
+        Some(_) => match bloom::fingerprint(&cur_path, id, size, limit, id_io) {
+            Ok(fingerprint) => fingerprint,
+            Err(err) => return counters.error(&cur_path, &err),
+        },
+        None => 0,
+    };
+    counters.record(|t| &t.ids, start);
+    // Inserted while holding the lock of the bucket the file goes in, which any copy of it would have to take too.
+    let seen = || {
+        counters
+            .bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.insert(fingerprint))
+    };
+
+    let paths = {
+        let mut map = lock(map);
+        let Some(paths) = map.get(&id) else {
+            seen();
+            log_unique(&cur_path);
+            let unique = Unique::new(cur_path, &metadata, order, known, reference, protected);
+            map.insert(id, Arc::new(Mutex::new(vec![unique])));
+            return;
+        };
+        Arc::clone(paths)
+    }; // release the map, only this bucket needs to stay locked
+
+    // The bucket stays locked from the first comparison until cur_path is either removed or pushed.
+    // Otherwise another thread could push a duplicate of cur_path after we compared against the bucket,
+    // leaving 2 identical files in the list of unique files.
+    let mut paths = lock(&paths);
+    let is_candidate = |old: &Unique| {
+        old.size.min(limit) == size.min(limit)
+            && (!options.per_dir || old.path.parent() == cur_path.parent())
+            && name
+                .as_ref()
+                .is_none_or(|name| *name == lowercase_name(&old.path))
+            && (!same_device || old.inode.dev == inode.dev)
+    };
+    if let Some(explain) = options.explain {
+        for old in paths
+            .iter()
+            .filter(|old| old.size.min(limit) != size.min(limit))
+        {
+            explain(&cur_path, &old.path, &Mismatch::Size(size, old.size));
+        }
+    }
+    let seen = seen();
+    if !paths.iter().any(is_candidate) {
+        log_unique(&cur_path);
+        // no file of the same length, so there's nothing worth reading
+        paths.push(Unique::new(
+            cur_path, &metadata, order, known, reference, protected,
+        ));
+        return;
+    }
+    if !seen {
+        counters.ruled_out.fetch_add(1, Relaxed);
+        log_unique(&cur_path);
+        paths.push(Unique::new(
+            cur_path, &metadata, order, known, reference, protected,
+        ));
+        return;
+    }
+    // Another hard link to a file that's already kept: removing it frees nothing and may break a deliberate link.
+    if let Some(old) = paths.iter().find(|old| inode.same_file(&old.inode)) {
+        log::info!(
+            "SAME {} (hard link to {})",
+            cur_path.display(),
+            old.path.display()
+        );
+        return;
+    }
+
+    // IDs only cover a prefix, so a bucket can hold many same-size files that differ further on;
+    // a cached digest per file rules those out without re-reading them for every comparison.
+    let start = counters.started();
+    let cur_io = counters.tallied(cur_io, |t| &t.compare_bytes);
+    let cur_hash =
+        match known.map_or_else(|| read_hash(&cur_path, options.digest, limit, cur_io), Ok) {
+            Ok(hash) => hash,
+            Err(err) => return counters.error(&cur_path, &err),
+        };
+    let mut cur_file = match cur_io.open(&cur_path) {
+        Ok(file) => file,
+        Err(err) => return counters.error(&cur_path, &err),
+    };
+    // Copies with the same extension are tried first, so one with another extension only matches if none of them does.
+    let cur_ext = options.same_ext_only.then(|| lowercase_ext(&cur_path));
+    let other_ext = |old: &Unique| {
+        cur_ext
+            .as_ref()
+            .is_some_and(|ext| *ext != lowercase_ext(&old.path))
+    };
+    let mut candidates: Vec<_> = (0..paths.len())
+        .filter(|&i| is_candidate(&paths[i]))
+        .collect();
+    candidates.sort_by_key(|&i| other_ext(&paths[i]));
+    let mut matched = None;
+    for i in candidates {
+        let old = &mut paths[i];
+        let old_gate = counters.io.gate(old.inode.dev);
+        let old_io = counters.tallied(Io::new(old_gate.as_deref(), options), |t| &t.compare_bytes);
+        let old_hash = match old.hash {
+            Some(hash) => hash,
+            None => match read_hash(&old.path, options.digest, limit, old_io) {
+                Ok(hash) => *old.hash.insert(hash),
+                Err(err) => {
+                    counters.error(&old.path, &err);
+                    continue; // unreadable files can't be confirmed as duplicates
+                }
+            },
+        };
+        if old_hash != cur_hash {
+            explain_difference(&cur_path, &old.path, limit, options);
+            continue;
+        }
+        if !options.byte_compare {
+            matched = Some(i);
+            break;
+        }
+        // Equal hashes are still confirmed byte for byte, so originals are only re-read for actual duplicates.
+        match old_io.open(&old.path).and_then(|mut old_file| {
+            contents_equal(&mut cur_file, &mut old_file, limit, (cur_io, old_io))
+        }) {
+            Ok(true) => {
+                matched = Some(i);
+                break;
+            }
+            Ok(false) => explain_difference(&cur_path, &old.path, limit, options),
+            Err(err) => counters.error(&old.path, &err),
+        }
+    }
+    drop(cur_file); // close before acting on cur_path, open files can't be removed on Windows
+    counters.record(|t| &t.compare, start);
+
+    let Some(i) = matched else {
+        log_unique(&cur_path);
+        paths.push(Unique::new(
+            cur_path,
+            &metadata,
+            order,
+            Some(cur_hash),
+            reference,
+            protected,
+        ));
+        return;
+    };
+    if other_ext(&paths[i]) {
+        counters.other_ext.fetch_add(1, Relaxed);
+        log::info!(
+            "OTHER EXTENSION {} (duplicate of {}, left in place)",
+            cur_path.display(),
+            paths[i].path.display()
+        );
+        paths.push(Unique::new(
+            cur_path,
+            &metadata,
+            order,
+            Some(cur_hash),
+            reference,
+            protected,
+        ));
+        return;
+    }
+    let old = &mut paths[i];
+    // Either file may have been written to since its ID was read, so the contents compared may not be what's acted upon.
+    let changed = [
+        (&cur_path, size, modified),
+        (&old.path, old.size, old.modified),
+    ]
+    .into_iter()
+    .find(|&(path, size, modified)| changed_since(path, size, modified));
+    if let Some((path, ..)) = changed {
+        counters.changed.fetch_add(1, Relaxed);
+        counters.forget(path);
+        log::warn!(
+            "{} is a duplicate of {}, left in place as {} changed during the scan",
+            cur_path.display(),
+            old.path.display(),
+            path.display()
+        );
+        return;
+    }
+    if reference && old.reference {
+        // Outside of delete_under these are duplicates that were asked to be removed elsewhere, worth knowing about.
+        let level = if counters.delete_under.is_empty() {
+            log::Level::Debug
+        } else {
+            log::Level::Info
+        };
+        log::log!(
+            level,
+            "REFERENCE {} (duplicate of {})",
+            cur_path.display(),
+            old.path.display()
+        );
+        return;
+    }
+
+    // Make cur_path the kept copy if the policy prefers it, the old one becomes the duplicate.
+    // References are never acted upon, and a real file always wins over a symlink,
+    // removing the real file could leave the link dangling.
+    let (cur_link, old_link) = (is_link(&cur_path, options), is_link(&old.path, options));
+    let mut keep_cur = match (reference, old.reference) {
+        (true, _) => true,
+        (_, true) => false,
+        _ => match (cur_link, old_link) {
+            (false, true) => true,
+            (true, false) => false,
+            _ => {
+                let pinned = |path: &Path| {
+                    (options.keep_path.as_ref()).map(|re| re.is_match(&path.to_string_lossy()))
+                };
+                match (pinned(&cur_path), pinned(&old.path)) {
+                    (Some(true), Some(false)) => true,
+                    (Some(false), Some(true)) => false,
+                    _ => options
+                        .keep
+                        .prefers(&cur_path, &old.path, order < old.order),
+                }
+            }
+        },
+    };
+    if !counters.protect.is_empty() {
+        let mut survivors = old.protected.clone();
+        survivors.extend(&protected);
+        let last_copy = |roots: &[usize]| {
+            roots
+                .iter()
+                .any(|root| survivors.iter().filter(|&kept| kept == root).count() == 1)
+        };
+        let old_roots = counters.protected_roots(&old.path);
+        let (cur_last, old_last) = (last_copy(&protected), last_copy(&old_roots));
+        let (dup_last, kept_last) = if keep_cur {
+            (old_last, cur_last)
+        } else {
+            (cur_last, old_last)
+        };
+        // Swapping is subject to the same rules as above, bar the policy.
+        let can_swap = if keep_cur {
+            !reference && (cur_link || !old_link)
+        } else {
+            !old.reference && (old_link || !cur_link)
+        };
+        if dup_last && (kept_last || !can_swap) {
+            old.protected = survivors;
+            if options.min_copies > 2 {
+                old.pending.push(Pending {
+                    path: cur_path,
+                    size,
+                    modified,
+                    protected: true,
+                });
+            } else {
+                log_protected(&cur_path, &old.path);
+            }
+            return;
+        }
+        if dup_last {
+            keep_cur = !keep_cur;
+        }
+        let dup_roots = if keep_cur { old_roots } else { protected };
+        for root in dup_roots {
+            if let Some(copy) = survivors.iter().position(|&kept| kept == root) {
+                survivors.swap_remove(copy);
+            }
+        }
+        old.protected = survivors;
+    }
+    let (dup, kept, dup_size, dup_modified) = if keep_cur {
+        (old.path.clone(), cur_path, old.size, old.modified)
+    } else {
+        (cur_path, old.path.clone(), size, modified)
+    };
+    if keep_cur {
+        old.path = kept.clone();
+        old.size = size;
+        old.inode = inode;
+        old.modified = modified;
+        old.order = order;
+        old.reference = reference;
+    }
+    if options.min_copies > 2 {
+        // Acted upon once every copy is known, see prune.
+        old.pending.push(Pending {
+            path: dup,
+            size: dup_size,
+            modified: dup_modified,
+            protected: false,
+        });
+        return;
+    }
+    counters.found.fetch_add(1, Relaxed); // before acting, so failed or declined actions still show up
+    let disposed = dispose(&dup, &kept, dup_size, id, options, counters);
+    if disposed && options.preserve_mtime {
+        // Possibly set to the duplicate's, which mustn't count as a change when the next copy is compared.
+        old.modified = std::fs::metadata(&kept).and_then(|m| m.modified()).ok();
+    }
+    if disposed && options.groups {
+        old.duplicates.push(dup);
+    }
+}
+
+/// Logs `dup` as left in place by [`Options::protect`], once it's known its group is acted upon.
+fn log_protected(dup: &Path, kept: &Path) {
+    log::info!(
+        "PROTECTED {} (duplicate of {}, the last copy in a protected directory)",
+        dup.display(),
+        kept.display()
+    );
+}
+
+/// Whether `path` no longer has the size and mtime it was scanned with; a file that can't be read counts as changed.
+fn changed_since(path: &Path, size: u64, modified: Option<SystemTime>) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len() != size || metadata.modified().ok() != modified,
+        Err(_) => true,
+    }
+}
+
+/// Finds where `a` and `b` first differ for [`Options::explain`], if it's set.
+/// Costs another read of both files up to there; failing that is only logged, the scan already dealt with both files.
+fn explain_difference(a: &Path, b: &Path, limit: u64, options: &Options) {
+    let Some(explain) = options.explain else {
+        return;
+    };
+    match first_difference(a, b, limit) {
+        Ok(Some(mismatch)) => explain(a, b, &mismatch),
+        Ok(None) => {} // rewritten since, or a digest collision
+        Err(err) => log::warn!(
+            "{}: can't explain the mismatch with {}: {err}",
+            a.display(),
+            b.display()
+        ),
+    }
+}
+
+/// The first byte where the first `limit` bytes of `a` and `b` differ, with some context.
+fn first_difference(a: &Path, b: &Path, limit: u64) -> Result<Option<Mismatch>, Error> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    const BEFORE: u64 = 8;
+    const CONTEXT: u64 = 24;
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let mut buf_a = vec![0u8; BUFSIZE];
+    let mut buf_b = vec![0u8; BUFSIZE];
+    let mut offset = 0;
+    loop {
+        let len_a = read_full(&mut (&mut a).take(limit - offset), &mut buf_a)?;
+        let len_b = read_full(&mut (&mut b).take(limit - offset), &mut buf_b)?;
+        let same = std::iter::zip(&buf_a[..len_a], &buf_b[..len_b])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if same < len_a.max(len_b) {
+            offset += same as u64;
+            break;
+        }
+        if len_a < BUFSIZE {
+            return Ok(None);
+        }
+        offset += len_a as u64;
+    }
+    let start = offset.saturating_sub(BEFORE);
+    let context = |file: &mut File| -> Result<Vec<u8>, Error> {
+        file.seek(SeekFrom::Start(start))?;
+        let mut bytes = Vec::new();
+        file.take(CONTEXT.min(limit - start))
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    };
+    Ok(Some(Mismatch::Bytes {
+        offset,
+        start,
+        a: context(&mut a)?,
+        b: context(&mut b)?,
+    }))
+}
+
+/// Removes the directories duplicates were removed from if they're empty now, then their parents likewise,
+/// up to the [`Options::prune_empty_dirs`] roots.
+fn prune_empty_dirs(options: &Options, counters: &Counters) {
+    let strictly_under = |dir: &Path| {
+        (options.prune_empty_dirs.iter()).any(|root| dir != root && dir.starts_with(root))
+    };
+    let emptied = std::mem::take(&mut *lock(&counters.emptied));
+    // Deepest first, so a parent is only tried once all its emptied subdirectories were.
+    let mut dirs: std::collections::BinaryHeap<_> = emptied
+        .into_iter()
+        .filter(|dir| strictly_under(dir))
+        .map(|dir| (dir.components().count(), dir))
+        .collect();
+    let mut tried = std::collections::HashSet::new();
+    while let Some((_, dir)) = dirs.pop() {
+        if !tried.insert(dir.clone()) {
+            continue; // emptied through several subdirectories
+        }
+        match retry_interrupts!(std::fs::remove_dir(&dir)) {
+            Ok(()) => {
+                log::info!("PRUNED {}", dir.display());
+                counters.pruned_dirs.fetch_add(1, Relaxed);
+                if let Some(parent) = dir.parent().filter(|parent| strictly_under(parent)) {
+                    dirs.push((parent.components().count(), parent.to_owned()));
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::DirectoryNotEmpty => {}
+            Err(err) => counters.error(&dir, &err),
+        }
+    }
+}
+
+/// Serializes picking a free name in the quarantine with taking it, so two workers can't pick the same one.
+static QUARANTINE: Mutex<()> = Mutex::new(());
+
+/// Moves `dup` to the same relative path under `dir`, or for an absolute path the path from its root,
+/// recreating the directories in between; `..` and the like are dropped, so nothing lands outside `dir`.
+/// A name that's taken gets a counter before its extension: `a.txt`, then `a-1.txt`, `a-2.txt`…
+/// Across devices `dup` is copied, keeping its mtime, then removed.
+fn move_to_quarantine(dup: &Path, dir: &Path, io: Io<'_>) -> Result<(), Error> {
+    use std::path::Component;
+
+    let relative: PathBuf = (dup.components())
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+    let target = dir.join(&relative);
+    if let Some(parent) = target.parent() {
+        retry_transient!(io, std::fs::create_dir_all(parent))?;
+    }
+    let _taking = lock(&QUARANTINE);
+    let mut free = target.clone();
+    for n in 1.. {
+        if free.symlink_metadata().is_err() {
+            break;
+        }
+        let stem = target.file_stem().unwrap_or_default().to_string_lossy();
+        free.set_file_name(match target.extension() {
+            Some(ext) => format!("{stem}-{n}.{}", ext.to_string_lossy()),
+            None => format!("{stem}-{n}"),
+        });
+    }
+    log::debug!("moving {} to {}", dup.display(), free.display());
+    match retry_transient!(io, std::fs::rename(dup, &free)) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {}
+        Err(err) => return Err(err),
+    }
+    let mtime = std::fs::metadata(dup)?.modified()?;
+    let copied = retry_transient!(io, std::fs::copy(dup, &free))
+        .and_then(|_| filetime::set_file_mtime(&free, filetime::FileTime::from_system_time(mtime)));
+    if let Err(err) = copied {
+        _ = remove_file(&free); // don't leave a partial copy behind
+        return Err(err);
+    }
+    retry_transient!(io, remove_file(dup)).map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!(
+                "copied to {} but failed to remove it: {err}",
+                free.display()
+            ),
+        )
+    })
+}
+
+/// Empties the map once a batch was scanned, collecting [`Summary::groups`] and [`Summary::hashes`] from it.
+/// The map keeps its capacity for the next batch.
+fn drain(map: &Map, options: &Options, counters: &Counters) {
+    let limit = options.compare_bytes.unwrap_or(u64::MAX);
+    let io = counters.tallied(Io::new(None, options), |t| &t.compare_bytes);
+    for (id, bucket) in lock(map).drain() {
+        if !(options.groups || options.hashes) {
+            continue;
+        }
+        let bucket = Arc::into_inner(bucket).expect("workers are done with every bucket");
+        let uniques = bucket.into_inner().unwrap_or_else(PoisonError::into_inner);
+        for unique in uniques {
+            if options.hashes {
+                // Only files that shared a bucket with another of their size were hashed during the scan.
+                let hash = match unique.hash {
+                    Some(hash) => hash,
+                    None => match read_hash(&unique.path, options.digest, limit, io) {
+                        Ok(hash) => hash,
+                        Err(err) => {
+                            counters.error(&unique.path, &err);
+                            continue;
+                        }
+                    },
+                };
+                lock(&counters.hashes).push(Hashed {
+                    path: unique.path.clone(),
+                    size: unique.size,
+                    hash,
+                    duplicates: unique.duplicates.clone(),
+                });
+            }
+            if options.groups && !unique.duplicates.is_empty() {
+                lock(&counters.groups).push(Group {
+                    id,
+                    size: unique.size,
+                    keeper: unique.path,
+                    duplicates: unique.duplicates,
+                });
+            }
+        }
+    }
+}
+
+/// Groups `paths` into batches of whole size classes for [`Options::shard_by_size`], each path with its place among `paths`.
+/// Classes are joined until a batch holds [`SHARD_FILES`], a larger one is a batch of its own.
+/// Paths whose size can't be read share the first batch, scanning them reports why.
+/// Sizes are capped at [`Options::compare_bytes`], as files are compared up to there.
+fn size_batches(paths: Vec<PathBuf>, options: &Options) -> Vec<Vec<Entry>> {
+    let limit = options.compare_bytes.unwrap_or(u64::MAX);
+    let mut classes: BTreeMap<Option<u64>, Vec<Entry>> = BTreeMap::new();
+    for (order, path) in paths.into_iter().enumerate() {
+        let metadata = retry_transient!(Io::new(None, options), std::fs::metadata(&path));
+        let size = metadata.ok().map(|metadata| metadata.len().min(limit));
+        classes.entry(size).or_default().push((order, path, None));
+    }
+    let mut batches: Vec<Vec<_>> = Vec::new();
+    for (_, class) in classes {
+        match batches.last_mut() {
+            Some(batch) if batch.len() + class.len() <= SHARD_FILES => batch.extend(class),
+            _ => batches.push(class),
+        }
+    }
+    batches
+}
+
+/// Second phase of [`Options::min_copies`]: acts on the duplicates of every group with enough copies.
+/// Only those are counted in [`Summary::found`], and any copy written to since the scan is left in place as it would have been then.
+fn prune(map: &Map, options: &Options, counters: &Counters) {
+    for (&id, bucket) in lock(map).iter() {
+        for unique in lock(bucket).iter_mut() {
+            if unique.pending.len() + 1 < options.min_copies {
+                continue;
+            }
+            for dup in std::mem::take(&mut unique.pending) {
+                if counters.stopped(options) {
+                    return;
+                }
+                if dup.protected {
+                    log_protected(&dup.path, &unique.path);
+                    continue;
+                }
+                let changed = [
+                    (&dup.path, dup.size, dup.modified),
+                    (&unique.path, unique.size, unique.modified),
+                ]
+                .into_iter()
+                .find(|&(path, size, modified)| changed_since(path, size, modified));
+                if let Some((path, ..)) = changed {
+                    counters.changed.fetch_add(1, Relaxed);
+                    counters.forget(path);
+                    log::warn!(
+                        "{} is a duplicate of {}, left in place as {} changed since the scan",
+                        dup.path.display(),
+                        unique.path.display(),
+                        path.display()
+                    );
+                    continue;
+                }
+                counters.found.fetch_add(1, Relaxed);
+                let disposed = dispose(&dup.path, &unique.path, dup.size, id, options, counters);
+                if disposed && options.preserve_mtime {
+                    unique.modified = std::fs::metadata(&unique.path)
+                        .and_then(|m| m.modified())
+                        .ok();
+                }
+                if disposed && options.groups {
+                    unique.duplicates.push(dup.path);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `path` is a symlink that's scanned as a file, see [`Options::follow_symlinks`].
+fn is_link(path: &Path, options: &Options) -> bool {
+    options.follow_symlinks && std::fs::symlink_metadata(path).is_ok_and(|m| m.is_symlink())
+}
+
+/// Carries out the action on `dup`, a verified duplicate of `kept`, asking first if [`Options::confirm`] is set.
+/// Returns whether it was acted upon, or would have been in a dry run.
+fn dispose(
+    dup: &Path,
+    kept: &Path,
+    size: u64,
+    id: ID,
+    options: &Options,
+    counters: &Counters,
+) -> bool {
+    // Reserved up front, so threads racing for the last slot can't overshoot the limit.
+    if counters.reserved.fetch_add(1, Relaxed) >= options.limit {
+        counters.reserved.fetch_sub(1, Relaxed);
+        log::warn!(
+            "{} is a duplicate of {}, left in place as the limit was reached",
+            dup.display(),
+            kept.display(),
+        );
+        return false;
+    }
+    let release = || _ = counters.reserved.fetch_sub(1, Relaxed);
+    if options.skip_open_files {
+        // The duplicate may be the finished copy of a download still being written to the kept one, so both count.
+        let busy = busy_reason(dup, Some(size)).map(|reason| (dup, reason));
+        if let Some((path, reason)) = busy.or_else(|| busy_reason(kept, None).map(|r| (kept, r))) {
+            release();
+            log::warn!(
+                "{} is a duplicate of {}, left in place as {} {reason}",
+                dup.display(),
+                kept.display(),
+                path.display(),
+            );
+            return false;
+        }
+    }
+    let dup_is_link = is_link(dup, options);
+    if let (Some(confirm), false) = (options.confirm, options.dry_run) {
+        if !counters.confirmed_all.load(Relaxed) {
+            match confirm(options.action, dup, kept) {
+                Confirm::Yes => {}
+                Confirm::No => {
+                    release();
+                    return false;
+                }
+                Confirm::All => counters.confirmed_all.store(true, Relaxed),
+                Confirm::Quit => {
+                    release();
+                    counters.stop.store(true, Relaxed);
+                    return false;
+                }
+            }
+        }
+    }
+    if options.verify_pass {
+        let limit = options.compare_bytes.unwrap_or(u64::MAX);
+        let gate = |file: &File| {
+            let dev = file
+                .metadata()
+                .map_or(0, |metadata| Inode::of(&metadata).dev);
+            counters.io.gate(dev)
+        };
+        let open = Io::new(None, options);
+        let start = counters.started();
+        let equal = open.open(dup).and_then(|mut dup_file| {
+            let mut kept_file = open.open(kept)?;
+            let (dup_gate, kept_gate) = (gate(&dup_file), gate(&kept_file));
+            let io = |gate| counters.tallied(Io::new(gate, options), |t| &t.compare_bytes);
+            let ios = (io(dup_gate.as_deref()), io(kept_gate.as_deref()));
+            contents_equal(&mut dup_file, &mut kept_file, limit, ios)
+        });
+        counters.record(|t| &t.compare, start);
+        match equal {
+            Ok(true) => {}
+            Ok(false) => {
+                release();
+                log::warn!(
+                    "{} no longer matches {}, left in place",
+                    dup.display(),
+                    kept.display()
+                );
+                return false;
+            }
+            Err(err) => {
+                release();
+                counters.error(dup, &err);
+                return false;
+            }
+        }
+    }
+    if options.dry_run {
+        if let Some(on_dry_run) = options.on_dry_run {
+            on_dry_run(dup, kept);
+        }
+    } else {
+        // Read before acting, dup's own mtime is gone afterwards.
+        let dup_mtime = (options.preserve_mtime && !counters.is_reference(kept))
+            .then(|| std::fs::metadata(dup).and_then(|m| m.modified()));
+        if let Some(Err(err)) = options.on_action.map(|f| f(options.action, dup, kept)) {
+            release();
+            counters.error(dup, &err);
+            return false;
+        }
+        let quarantine = options.quarantine.as_deref();
+        let start = counters.started();
+        if let Err(err) = apply(
+            options.action,
+            dup,
+            kept,
+            quarantine,
+            Io::new(None, options),
+        ) {
+            release();
+            counters.error(dup, &err);
+            return false;
+        }
+        counters.forget(dup);
+        if let Some(dup_mtime) = dup_mtime {
+            if let Err(err) = preserve_mtime(dup_mtime, kept) {
+                log::warn!("{}: failed to preserve mtime: {err}", kept.display());
+            }
+        }
+        counters.record(|t| &t.act, start);
+        if matches!(
+            options.action,
+            Action::Delete | Action::Trash | Action::Quarantine
+        ) {
+            if let Some(parent) = dup
+                .parent()
+                .filter(|_| !options.prune_empty_dirs.is_empty())
+            {
+                lock(&counters.emptied).insert(parent.to_owned());
+            }
+        }
+    }
+    log::info!(
+        "{} {} (duplicate of {})",
+        options.action.label(options.dry_run).to_uppercase(),
+        dup.display(),
+        kept.display(),
+    );
+    let freed = if dup_is_link { 0 } else { size }; // removing a symlink frees nothing of its target
+    counters.deleted.fetch_add(1, Relaxed);
+    counters.bytes_freed.fetch_add(freed, Relaxed);
+    let ext = dup
+        .extension()
+        .map_or(String::new(), |ext| ext.to_string_lossy().to_lowercase());
+    let mut extensions = lock(&counters.extensions);
+    let stats = extensions.entry(ext).or_default();
+    stats.deleted += 1;
+    stats.bytes_freed += freed;
+    drop(extensions);
+    if options.records || options.on_record.is_some() {
+        let record = Record {
+            path: dup.to_owned(),
+            original: kept.to_owned(),
+            size,
+            id,
+        };
+        if let Some(on_record) = options.on_record {
+            on_record(&record);
+        }
+        if options.records {
+            lock(&counters.records).push(record);
+        }
+    }
+    true
+}
+
+/// How long ago a file must have last been modified to not count as being written, see [`Options::skip_open_files`].
+pub const RECENT: Duration = Duration::from_secs(2);
+
+/// Suffixes browsers and download managers give files until they're complete.
+const PARTIAL_SUFFIXES: [&str; 5] = [".part", ".partial", ".crdownload", ".download", ".tmp"];
+
+/// Why `path` looks like it's still being written, if it does; `size` is what it had when scanned.
+fn busy_reason(path: &Path, size: Option<u64>) -> Option<&'static str> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    if PARTIAL_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+        return Some("is named like an unfinished download");
+    }
+    let metadata = std::fs::metadata(path).ok()?; // acting on it reports the error
+    if size.is_some_and(|size| size != metadata.len()) {
+        return Some("changed size since it was scanned");
+    }
+    let age = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.elapsed().ok());
+    if age.is_some_and(|age| age < RECENT) {
+        return Some("was modified moments ago");
+    }
+    let locked = File::open(path)
+        .is_ok_and(|file| matches!(file.try_lock(), Err(std::fs::TryLockError::WouldBlock)));
+    locked.then_some("is locked by another process")
+}
+
+/// Where a file is stored: its filesystem and its number on it.
+/// Only known on Unix, the Windows equivalents are only available on nightly; elsewhere both are 0.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Inode {
+    /// Compared for [`Options::same_device`].
+    dev: u64,
+    ino: u64,
+}
+
+impl Inode {
+    #[cfg(unix)]
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn of(_: &std::fs::Metadata) -> Self {
+        Self { dev: 0, ino: 0 }
+    }
+
+    /// Whether both are links to the same file; never true where inodes aren't known.
+    fn same_file(&self, other: &Self) -> bool {
+        cfg!(unix) && self == other
+    }
+}
+
+/// The file name of `path` in lowercase, see [`Options::by_name`].
+fn lowercase_name(path: &Path) -> String {
+    path.file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().to_lowercase())
+}
+
+/// The extension of `path` in lowercase, see [`Options::same_ext_only`].
+fn lowercase_ext(path: &Path) -> String {
+    path.extension()
+        .map_or(String::new(), |ext| ext.to_string_lossy().to_lowercase())
+}
+
+fn log_unique(path: &Path) {
+    log::debug!("KEPT {}", path.display());
+}
+
+/// Hashes the first `limit` bytes of the file with `digest`.
+fn read_hash(path: &Path, digest: Digest, limit: u64, io: Io<'_>) -> Result<Hash, Error> {
+    use sha2::Digest as _;
+    use std::io::Read;
+
+    let mut file = io.reader(io.open(path)?).take(limit);
+    let mut buf = vec![0u8; BUFSIZE];
+    let mut blake3 = blake3::Hasher::new();
+    let mut sha256 = sha2::Sha256::new();
+
+    loop {
+        let chunk = match retry_interrupts!(file.read(&mut buf))? {
+            0 => break,
+            n => &buf[..n],
+        };
+        match digest {
+            Digest::Blake3 => _ = blake3.update(chunk),
+            Digest::Sha256 => sha256.update(chunk),
+        }
+    }
+    Ok(match digest {
+        Digest::Blake3 => blake3.finalize().into(),
+        Digest::Sha256 => sha256.finalize().into(),
+    })
+}
+
+/// Compares the first `limit` bytes of two files block by block, bailing out on the first difference.
+/// `a` is rewound first, so it can be compared against several files in turn.
+fn contents_equal(
+    a: &mut File,
+    b: &mut File,
+    limit: u64,
+    (a_io, b_io): (Io<'_>, Io<'_>),
+) -> Result<bool, Error> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    retry_transient!(a_io, a.seek(SeekFrom::Start(0)))?;
+    let a = &mut a_io.reader(a).take(limit);
+    let b = &mut b_io.reader(b).take(limit);
+    let mut buf_a = vec![0u8; BUFSIZE];
+    let mut buf_b = vec![0u8; BUFSIZE];
+
+    loop {
+        let len_a = read_full(a, &mut buf_a)?;
+        let len_b = read_full(b, &mut buf_b)?;
+        if buf_a[..len_a] != buf_b[..len_b] {
+            return Ok(false);
+        }
+        if len_a < BUFSIZE {
+            return Ok(true); // both files hit EOF, as the lengths are equal
+        }
+    }
+}
+
+/// Most files [`scan_file`] has open at once: the file scanned and one it's compared with, plus both again in [`Options::explain`].
+const FILES_PER_SCAN: usize = 3;
+
+/// Hands out a fixed number of permits, blocking whoever asks while none are left.
+struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: NonZeroUsize) -> Self {
+        Self {
+            permits: Mutex::new(permits.get()),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Permit<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Takes `n` permits at once, so two callers can't each hold part of what the other needs.
+    fn acquire_many(&self, n: usize) -> Permit<'_> {
+        let mut permits = lock(&self.permits);
+        while *permits < n {
+            permits = self
+                .released
+                .wait(permits)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *permits -= n;
+        Permit(self, n)
+    }
+}
+
+/// Gives its permits back on drop.
+struct Permit<'a>(&'a Semaphore, usize);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *lock(&self.0.permits) += self.1;
+        self.0.released.notify_all(); // whoever waits for fewer may go first
+    }
+}
+
+/// One [`Semaphore`] per device, created on first use, see [`Options::io_concurrency`].
+#[derive(Default)]
+struct IoLimits {
+    per_device: Option<NonZeroUsize>,
+    /// `None` for devices whose reads aren't limited.
+    devices: Mutex<HashMap<u64, Option<Arc<Semaphore>>>>,
+}
+
+impl IoLimits {
+    fn new(per_device: Option<NonZeroUsize>) -> Self {
+        Self {
+            per_device,
+            devices: Default::default(),
+        }
+    }
+
+    fn gate(&self, dev: u64) -> Option<Arc<Semaphore>> {
+        let mut devices = lock(&self.devices);
+        let gate = devices.entry(dev).or_insert_with(|| {
+            let permits = match self.per_device {
+                Some(permits) => permits,
+                None if is_rotational(dev) => NonZeroUsize::MIN,
+                None => return None,
+            };
+            Some(Arc::new(Semaphore::new(permits)))
+        });
+        gate.clone()
+    }
+}
+
+/// Whether `dev` is a spinning disk, according to sysfs.
+#[cfg(target_os = "linux")]
+fn is_rotational(dev: u64) -> bool {
+    // glibc's encoding of dev_t, see major(3).
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+    let block = format!("/sys/dev/block/{major}:{minor}");
+    // Partitions have no queue of their own, the disk they're on is their parent.
+    [
+        format!("{block}/queue/rotational"),
+        format!("{block}/../queue/rotational"),
+    ]
+    .iter()
+    .find_map(|path| std::fs::read_to_string(path).ok())
+    .is_some_and(|rotational| rotational.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_: u64) -> bool {
+    false
+}
+
+/// How to access files on one device: the [`Semaphore`] limiting reads there, if any, and how to retry.
+#[derive(Clone, Copy)]
+struct Io<'a> {
+    gate: Option<&'a Semaphore>,
+    retry: Retry,
+    /// Set for [`OnError::Retry`].
+    retry_all: bool,
+    /// Bytes read and read calls, with [`Options::profile`]; see [`Counters::tallied`].
+    tally: Option<(&'a AtomicU64, &'a AtomicU64)>,
+}
+
+impl<'a> Io<'a> {
+    fn new(gate: Option<&'a Semaphore>, options: &Options) -> Self {
+        Self {
+            gate,
+            retry: options.retry,
+            retry_all: options.on_error == OnError::Retry,
+            tally: None,
+        }
+    }
+
+    /// Whether `err` may clear up by itself, see [`Retry`].
+    fn retries(self, err: &Error) -> bool {
+        self.retry_all
+            || matches!(
+                err.kind(),
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ResourceBusy
+            )
+    }
+
+    fn open(self, path: &Path) -> Result<File, Error> {
+        retry_transient!(self, File::open(path))
+    }
+
+    fn reader<R: std::io::Read>(self, inner: R) -> Throttled<'a, R> {
+        Throttled { inner, io: self }
+    }
+}
+
+/// Takes a permit from the gate, if any, around each read, and retries transient errors.
+struct Throttled<'a, R> {
+    inner: R,
+    io: Io<'a>,
+}
+
+impl<R: std::io::Read> std::io::Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let _permit = self.io.gate.map(Semaphore::acquire);
+        let len = retry_transient!(self.io, self.inner.read(buf))?;
+        if let Some((bytes, reads)) = self.io.tally {
+            bytes.fetch_add(len as u64, Relaxed);
+            reads.fetch_add(1, Relaxed);
+        }
+        Ok(len)
+    }
+}
+
+/// Reads until `buf` is full or EOF is reached, returning the amount read.
+fn read_full(reader: &mut impl std::io::Read, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match retry_interrupts!(reader.read(&mut buf[filled..]))? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Sets the mtime of `kept` to `dup_mtime` if that's older.
+fn preserve_mtime(
+    dup_mtime: Result<std::time::SystemTime, Error>,
+    kept: &Path,
+) -> Result<(), Error> {
+    let dup_mtime = dup_mtime?;
+    if std::fs::metadata(kept)?.modified()? > dup_mtime {
+        filetime::set_file_mtime(kept, filetime::FileTime::from_system_time(dup_mtime))?;
+    }
+    Ok(())
+}
+
+/// Path of `original` as seen from the directory of `dup`: relative if they share a directory above the root, absolute otherwise.
+/// Both are canonicalized first, so `..` and symlinked directories along either path can't leave the link dangling.
+fn link_target(dup: &Path, original: &Path) -> Result<PathBuf, Error> {
+    use std::path::Component;
+
+    let dir = match dup.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = std::fs::canonicalize(dir)?;
+    let original = std::fs::canonicalize(original)?;
+    let common = dir
+        .components()
+        .zip(original.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let shares_dir = dir
+        .components()
+        .take(common)
+        .any(|c| matches!(c, Component::Normal(_)));
+    if !shares_dir {
+        return Ok(original);
+    }
+    let up = dir.components().count() - common;
+    Ok(std::iter::repeat_n(Component::ParentDir.as_os_str(), up)
+        .chain(original.components().skip(common).map(|c| c.as_os_str()))
+        .collect())
+}
+
+#[cfg(unix)]
+fn symlink_file(target: &Path, link: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(target: &Path, link: &Path) -> Result<(), Error> {
+    std::os::windows::fs::symlink_file(target, link).map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!("{err} (creating symlinks takes Developer Mode or the symlink privilege)"),
+        )
+    })
+}
+
+/// Carries out `action` on `dup`, a verified duplicate of `original`.
+fn apply(
+    action: Action,
+    dup: &Path,
+    original: &Path,
+    quarantine: Option<&Path>,
+    io: Io<'_>,
+) -> Result<(), Error> {
+    match action {
+        Action::Delete => retry_transient!(io, remove_file(dup)),
+        Action::Trash => trash::delete(dup).map_err(Error::other),
+        Action::Quarantine => {
+            let dir = quarantine.ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "no quarantine directory was given")
+            })?;
+            move_to_quarantine(dup, dir, io)
+        }
+        Action::Hardlink | Action::Symlink => {
+            let target = match action {
+                Action::Symlink => link_target(dup, original)?,
+                _ => original.to_owned(),
+            };
+            retry_transient!(io, remove_file(dup))?;
+            // dup is gone at this point, so a failure here needs more than the usual one-line error.
+            let linked = match action {
+                Action::Symlink => retry_transient!(io, symlink_file(&target, dup)),
+                _ => retry_transient!(io, std::fs::hard_link(&target, dup)),
+            };
+            // A link that doesn't lead to original is as good as a deletion, so it's an error too.
+            let linked = linked.and_then(|()| std::fs::metadata(dup).map(drop));
+            linked.map_err(|err| {
+                Error::new(
+                    err.kind(),
+                    format!(
+                        "removed {} but failed to link it to {}: {err}; its contents only remain in {}",
+                        dup.display(),
+                        original.display(),
+                        original.display(),
+                    ),
+                )
+            })
+        }
+    }
+}
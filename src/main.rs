@@ -4,131 +4,1654 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use nohash_hasher::IntMap as HashMap;
-use std::fs::{read, read_dir, remove_file};
+mod config;
+mod watch;
+
+use config::Config;
+use file_cleaner::{
+    deduplicate, deduplicate_hashed, find_similar, hash_files, Action, Confirm, Empty, Group,
+    Hashed, Mismatch, OnError, Options, PathError, Record, SimilarSummary, Summary, Walk,
+};
+use globset::{Glob, GlobSetBuilder};
+use std::fs::File;
+use std::io::Write;
 use std::io::{Error, ErrorKind};
-use std::mem::{size_of, MaybeUninit};
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// Undo log opened by --log, written to by [`log_action`].
+static LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Where results go: the summary, and the matches and mismatches printed along the way. Set by --report, stdout otherwise.
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+
+/// Like `println!`, to the [`REPORTER`].
+macro_rules! report {
+    ($($arg:tt)*) => {
+        Reporter::get().line(format_args!($($arg)*))
+    };
+}
+
+/// Set by the Ctrl+C handler.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit code after an interrupted run, as shells report for SIGINT.
+const EXIT_INTERRUPTED: u8 = 130;
+
+/// Exit code when no command or directory was given, as for other usage errors.
+const EXIT_USAGE: u8 = 2;
+
+const USAGE: &str = "\
+Usage: file_cleaner scan [OPTIONS] <DIR>...
+       file_cleaner clean [OPTIONS] <DIR>...
+       file_cleaner restore <LOG>
+       file_cleaner hash [OPTIONS] <DIR>...
+
+Commands:
+  scan      report files that duplicate another file, without touching anything
+  clean     delete (or otherwise dispose of) those duplicates
+  restore   copy originals back to the duplicates recorded by clean --log
+  hash      print a checksum line per file, for sha256sum -c (with --digest sha256) or b3sum -c
+
+Scan, clean and hash can take --from-file <PATH> or --from-stdin in place of directories.
+Scan and clean can take --from-hashes <PATH> instead, of <hash>\t<path> lines with the --digest of each file,
+to compare those hashes without reading the files; matches are confirmed byte for byte unless --verify is given.
+
+Common options:
+  -r, --recursive        scan subdirectories too
+      --max-depth <N>    scan subdirectories down to N levels below each directory given, 0 for none
+      --dry-run          with clean, report what it would do, same as scan
+      --trash            move duplicates to the trash instead of deleting them
+      --hardlink         replace duplicates with hard links to the kept copy
+      --symlink          replace duplicates with symbolic links to the kept copy
+      --delete-to <DIR>  move duplicates under DIR instead, keeping their paths
+      --keep <POLICY>    first, oldest, newest or shortest-path
+      --keep-path <RE>   keep the copy whose path matches RE, whatever the policy
+      --sort <ORDER>     scan in name, mtime, size or path order, for the same keeper every run
+      --reference <DIR>  also scan DIR, but never touch its files
+      --protect <DIR>    also scan DIR, and always leave a copy of its files in it
+      --emit-script <PATH>
+                         write a script removing the duplicates to PATH, and touch nothing
+      --delete-under <DIR>
+                         also scan DIR, and only act on duplicates in it
+      --no-ignore        don't skip what .cleanerignore files match
+      --watch            keep going with files that appear in DIRs, until interrupted
+  -i, --interactive      ask before each duplicate
+  -q, --quiet            print errors only
+  -v, --verbose          print every file
+      --config <PATH>    read defaults from PATH instead of file_cleaner.toml
+  -h, --help             print this message
+
+Choosing files:
+      --exclude <GLOB>   skip paths or names matching GLOB, can be repeated
+      --ext <EXT>        only scan files with extension EXT, can be repeated
+      --min-size <SIZE>  only scan files of at least SIZE, e.g. 10K or 1.5MB
+      --max-size <SIZE>  only scan files of at most SIZE
+      --newer-than <TIME>
+                         only scan files modified after TIME, an age like 2d or a date like 2024-01-31
+      --older-than <TIME>
+                         only scan files modified before TIME
+      --empty <MODE>     skip, dedup or report empty files; skip by default
+      --include-hidden   scan hidden files and directories too
+      --follow-symlinks  scan what symlinks to files point to, instead of skipping them
+      --skip-dir <DIR>   don't descend into DIR, a name like node_modules or a path, can be repeated
+      --scan-parent      scan the directory of a file given in place of a directory
+      --walk-threads <N> list N directories at once, paths then come in no set order
+
+Telling copies apart:
+      --digest <DIGEST>  hash files with blake3 (the default) or sha256
+      --verify <DIGEST>  trust equal hashes with DIGEST, instead of comparing byte for byte
+      --byte-compare     compare equal hashes byte for byte, even with --verify
+      --compare-bytes <SIZE>
+                         only compare the first SIZE of each file, not with --verify
+      --prefix-bytes <SIZE>
+                         group files by a hash of their first SIZE, 4K by default
+      --bloom            rule unique files out by a fingerprint of their size and end first
+      --same-device      only treat files on the same filesystem as copies
+      --keep-one-per-dir only treat files in the same directory as copies
+      --by-name          only treat files with the same name, ignoring case, as copies
+      --same-ext-only    leave copies with another extension alone
+      --explain          print where files that start alike differ
+      --similar          scan only: report files whose contents are mostly alike instead
+      --similarity-threshold <SHARE>
+                         how alike for --similar, e.g. 0.9 or 90%; 80% by default
+
+Acting on duplicates:
+      --min-copies <N>   only act on files with at least N copies, counting the kept one
+      --limit <N>        act on at most N duplicates, report the rest
+      --preserve-mtime   give the kept copy the mtime of an older duplicate
+      --skip-open-files  leave copies alone that look like they're still being written
+      --verify-pass      compare each duplicate with its kept copy again right before acting
+      --prune-empty-dirs remove directories emptied by deleting duplicates
+      --log <PATH>       append what was done to PATH, for restore
+      --on-error <MODE>  skip, retry or abort on a failed read or action; skip by default
+      --fail-fast        same as --on-error abort
+      --retries <N>      retry transient failures N times
+      --retry-delay <MS> wait MS milliseconds before the first retry, doubling for each
+  -j, --threads <N>      scan N files at once
+      --max-open-files <N>
+                         keep at most N files open at once
+      --io-concurrency <N>
+                         read at most N files at once per device
+      --shard-by-size    scan size classes in turn, holding fewer files in memory
+      --count-first      find every path before scanning, so progress shows a total
+
+Reporting:
+      --json             print the summary and what was acted upon as JSON
+      --ndjson           print a JSON line per duplicate acted upon and failure, then the summary
+      --report <PATH>    print results to PATH instead of stdout
+      --tree             print each kept file with its duplicates under it
+      --copies-report    print every set of copies, largest waste first, and touch nothing
+      --manifest <PATH>  write a CSV row per file of every set of copies to PATH, and touch nothing
+      --emit-hashes <PATH>
+                         write the hash, size and path of every kept file to PATH
+      --emit-duplicates  with --emit-hashes, write the duplicates acted upon too
+      --profile          print where the time went, and how much was read
+      --progress-interval <MS>
+                         print progress at most every MS milliseconds
+";
+
+/// The first argument, saying what to do; the destructive one must be asked for by name.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Scan,
+    Clean,
+    Restore,
+    Hash,
+}
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(code) => code,
+        Err(err) => {
+            // Same exit code as returning the error, without its debug formatting.
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn try_main() -> Result<ExitCode, Error> {
+    let command = match std::env::args_os()
+        .nth(1)
+        .as_ref()
+        .and_then(|arg| arg.to_str())
+    {
+        Some("scan") => Command::Scan,
+        Some("clean") => Command::Clean,
+        Some("restore") => Command::Restore,
+        Some("hash") => Command::Hash,
+        Some("--help" | "-h") => {
+            print!("{USAGE}");
+            return Ok(ExitCode::SUCCESS);
+        }
+        _ => {
+            eprint!("{USAGE}");
+            return Ok(ExitCode::from(EXIT_USAGE));
+        }
+    };
+    let mut dirs = Vec::new();
+    let mut recursive = false;
+    let mut report = Report::Text;
+    let mut verbose = false;
+    let mut byte_compare = false;
+    let mut manifest = None;
+    let mut script = None;
+    let mut hashes = None;
+    let mut hash_duplicates = false;
+    let mut similar = None;
+    let mut no_ignore = false;
+    let mut prune_empty_dirs = false;
+    let mut skip_dirs = Vec::new();
+    let mut walk_threads = NonZeroUsize::MIN;
+    let mut max_depth = None;
+    let mut list = None;
+    let mut hashed = None;
+    let mut log = None;
+    let mut scan_parent = false;
+    let mut tree = false;
+    let mut copies = false;
+    let mut watch = false;
+    let mut exclude = GlobSetBuilder::new();
+    let mut options = Options::default();
+
+    // Loaded before the other flags are parsed, so each of them overrides the file.
+    let mut args = std::env::args_os().skip(2);
+    let config = args
+        .by_ref()
+        .skip_while(|arg| arg != "--config")
+        .nth(1)
+        .map(PathBuf::from);
+    Config::load(config.as_deref())?.apply(&mut options, &mut recursive, &mut exclude)?;
+
+    let mut args = std::env::args_os().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--recursive" | "-r") => recursive = true,
+            Some("--max-depth") => max_depth = Some(parse_value(&mut args, "--max-depth")?),
+            Some("--dry-run") => options.dry_run = true,
+            Some("--trash") => options.action = Action::Trash,
+            Some("--hardlink") => options.action = Action::Hardlink,
+            Some("--symlink") => options.action = Action::Symlink,
+            Some("--delete-to") => {
+                options.action = Action::Quarantine;
+                options.quarantine = Some(parse_value(&mut args, "--delete-to")?);
+            }
+            Some("--json") => report = Report::Json,
+            Some("--ndjson") => report = Report::Ndjson,
+            Some("--quiet" | "-q") => report = Report::Quiet,
+            Some("--tree") => tree = true,
+            Some("--copies-report") => copies = true,
+            Some("--verbose" | "-v") => verbose = true,
+            Some("--interactive" | "-i") => options.confirm = Some(prompt),
+            Some("--exclude") => {
+                let pattern: String = parse_value(&mut args, "--exclude")?;
+                exclude.add(Glob::new(&pattern).map_err(|err| {
+                    Error::new(ErrorKind::InvalidInput, format!("--exclude: {err}"))
+                })?);
+            }
+            Some("--ext") => {
+                let ext: String = parse_value(&mut args, "--ext")?;
+                options
+                    .extensions
+                    .push(ext.trim_start_matches('.').to_owned());
+            }
+            Some("--min-size") => {
+                options.min_size = parse_value::<Size>(&mut args, "--min-size")?.0
+            }
+            Some("--newer-than") => {
+                options.modified_after = Some(parse_value::<Time>(&mut args, "--newer-than")?.0)
+            }
+            Some("--older-than") => {
+                options.modified_before = Some(parse_value::<Time>(&mut args, "--older-than")?.0)
+            }
+            Some("--max-size") => {
+                options.max_size = parse_value::<Size>(&mut args, "--max-size")?.0
+            }
+            Some("--no-ignore") => no_ignore = true,
+            Some("--walk-threads") => walk_threads = parse_value(&mut args, "--walk-threads")?,
+            Some("--skip-dir") => skip_dirs.push(parse_value(&mut args, "--skip-dir")?),
+            Some("--prune-empty-dirs") => prune_empty_dirs = true,
+            Some("--include-hidden") => options.include_hidden = true,
+            Some("--follow-symlinks") => options.follow_symlinks = true,
+            Some("--digest") => options.digest = parse_value(&mut args, "--digest")?,
+            Some("--verify") => {
+                options.digest = parse_value(&mut args, "--verify")?;
+                options.byte_compare = false;
+            }
+            Some("--compare-bytes") => {
+                options.compare_bytes = Some(parse_value::<Size>(&mut args, "--compare-bytes")?.0)
+            }
+            Some("--fail-fast") => options.on_error = OnError::Abort,
+            Some("--on-error") => options.on_error = parse_value(&mut args, "--on-error")?,
+            Some("--explain") => options.explain = Some(print_mismatch),
+            Some("--skip-open-files") => options.skip_open_files = true,
+            Some("--verify-pass") => options.verify_pass = true,
+            Some("--byte-compare") => byte_compare = true,
+            Some("--from-file") => list = Some(List::File(parse_value(&mut args, "--from-file")?)),
+            Some("--scan-parent") => scan_parent = true,
+            Some("--from-stdin") => list = Some(List::Stdin),
+            Some("--from-hashes") => {
+                hashed = Some(parse_value::<PathBuf>(&mut args, "--from-hashes")?)
+            }
+            Some("--log") => log = Some(parse_value::<PathBuf>(&mut args, "--log")?),
+            Some("--manifest") => manifest = Some(parse_value::<PathBuf>(&mut args, "--manifest")?),
+            Some("--emit-script") => {
+                script = Some(parse_value::<PathBuf>(&mut args, "--emit-script")?)
+            }
+            Some("--emit-hashes") => {
+                hashes = Some(parse_value::<PathBuf>(&mut args, "--emit-hashes")?)
+            }
+            Some("--emit-duplicates") => hash_duplicates = true,
+            Some("--similar") => similar = similar.or(Some(0.8)),
+            Some("--similarity-threshold") => {
+                let Percent(threshold) = parse_value(&mut args, "--similarity-threshold")?;
+                similar = Some(threshold);
+            }
+            Some("--report") => {
+                let dest = parse_value::<PathBuf>(&mut args, "--report")?;
+                // Created up front, so a bad path fails before the scan rather than after it.
+                if REPORTER.set(Reporter::open(&dest)?).is_err() {
+                    return Err(Error::new(ErrorKind::InvalidInput, "--report given twice"));
+                }
+            }
+            Some("--progress-interval") => {
+                let millis = parse_value(&mut args, "--progress-interval")?;
+                options.progress_interval = Duration::from_millis(millis);
+            }
+            Some("--retries") => options.retry.attempts = parse_value(&mut args, "--retries")?,
+            Some("--retry-delay") => {
+                let millis = parse_value(&mut args, "--retry-delay")?;
+                options.retry.delay = Duration::from_millis(millis);
+            }
+            Some("--count-first") => options.count_first = true,
+            Some("--shard-by-size") => options.shard_by_size = true,
+            Some("--watch") => watch = true,
+            Some("--bloom") => options.bloom = true,
+            Some("--prefix-bytes") => {
+                let Size(bytes) = parse_value(&mut args, "--prefix-bytes")?;
+                options.prefix_bytes = NonZeroU64::new(bytes).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--prefix-bytes must be at least 1")
+                })?;
+            }
+            Some("--profile") => options.profile = true,
+            Some("--limit") => options.limit = parse_value(&mut args, "--limit")?,
+            Some("--min-copies") => options.min_copies = parse_value(&mut args, "--min-copies")?,
+            Some("--reference") => options
+                .references
+                .push(parse_value(&mut args, "--reference")?),
+            Some("--protect") => options.protect.push(parse_value(&mut args, "--protect")?),
+            Some("--delete-under") => {
+                (options.delete_under).push(parse_value(&mut args, "--delete-under")?)
+            }
+            Some("--preserve-mtime") => options.preserve_mtime = true,
+            Some("--same-device") => options.same_device = true,
+            Some("--keep-one-per-dir") => options.per_dir = true,
+            Some("--by-name") => options.by_name = true,
+            Some("--same-ext-only") => options.same_ext_only = true,
+            Some("--empty") => options.empty = parse_value(&mut args, "--empty")?,
+            Some("--keep") => options.keep = parse_value(&mut args, "--keep")?,
+            Some("--keep-path") => {
+                let pattern: String = parse_value(&mut args, "--keep-path")?;
+                options.keep_path = Some(regex::Regex::new(&pattern).map_err(|err| {
+                    Error::new(ErrorKind::InvalidInput, format!("--keep-path: {err}"))
+                })?);
+            }
+            Some("--sort") => options.sort = Some(parse_value(&mut args, "--sort")?),
+            Some("--threads" | "-j") => options.threads = parse_value(&mut args, "--threads")?,
+            Some("--max-open-files") => {
+                options.max_open_files = Some(parse_value(&mut args, "--max-open-files")?)
+            }
+            Some("--io-concurrency") => {
+                options.io_concurrency = Some(parse_value(&mut args, "--io-concurrency")?)
+            }
+            Some("--help" | "-h") => {
+                print!("{USAGE}");
+                return Ok(ExitCode::SUCCESS);
+            }
+            Some("--config") => {
+                parse_value::<PathBuf>(&mut args, "--config")?; // already loaded
+            }
+            _ => dirs.push(arg),
+        }
+    }
+    init_logger(verbose, report);
+    recursive |= max_depth.is_some(); // a depth to recurse to says to recurse
+
+    match (command, &dirs[..]) {
+        (Command::Restore, [log]) => return restore_from(Path::new(log)),
+        (Command::Restore, _) => {
+            eprint!("{USAGE}");
+            return Ok(ExitCode::from(EXIT_USAGE));
+        }
+        (Command::Scan | Command::Hash, _) => options.dry_run = true,
+        (Command::Clean, _) => {}
+    }
+    if list.is_some() && !dirs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--from-file and --from-stdin replace the directories to scan, they can't be combined",
+        ));
+    }
+    if matches!(list, Some(List::Stdin)) && options.confirm.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--interactive reads answers from stdin, it can't be combined with --from-stdin",
+        ));
+    }
+    if hashed.is_some() && (list.is_some() || !dirs.is_empty() || command == Command::Hash) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--from-hashes replaces the directories to scan, it can't be combined with them, another list or hash",
+        ));
+    }
+    if dirs.is_empty() && list.is_none() && hashed.is_none() {
+        eprint!("{USAGE}");
+        return Ok(ExitCode::from(EXIT_USAGE));
+    }
+    if scan_parent {
+        for dir in &mut dirs {
+            if Path::new(dir).is_file() {
+                *dir = match Path::new(dir).parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent.into(),
+                    _ => ".".into(), // a bare file name is in the current directory
+                };
+            }
+        }
+    }
+    if options.min_size > options.max_size {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--min-size is larger than --max-size",
+        ));
+    }
+    // The action is settled by now, a delete-to in the config may well be overridden by --trash and the like.
+    match &options.quarantine {
+        _ if options.action != Action::Quarantine => {}
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the quarantine action takes a directory, give it with --delete-to or delete-to in the config",
+            ));
+        }
+        // Created up front, so a bad path fails before the scan rather than on the first duplicate.
+        Some(dir) if !options.dry_run => {
+            std::fs::create_dir_all(dir).map_err(|err| PathError::wrap("creating", dir, err))?
+        }
+        Some(_) => {}
+    }
+    if prune_empty_dirs {
+        if list.is_some() || hashed.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--prune-empty-dirs only prunes under the directories scanned, it can't be combined with a list",
+            ));
+        }
+        // References are never acted upon, so nothing under them is emptied.
+        options.prune_empty_dirs = (dirs.iter().map(PathBuf::from))
+            .chain(options.protect.iter().cloned())
+            .chain(options.delete_under.iter().cloned())
+            .collect();
+    }
+    options.exclude = exclude
+        .build()
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("--exclude: {err}")))?;
+    options.byte_compare |= byte_compare; // --byte-compare wins over --verify, whatever the order
+    if options.compare_bytes.is_some() && !options.byte_compare {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--compare-bytes only checks a prefix, it can't be combined with --verify",
+        ));
+    }
+
+    // Created up front, so a bad path fails before the scan rather than after it.
+    let manifest = match manifest {
+        Some(path) => {
+            options.dry_run = true; // the manifest lists every copy, so none may be removed
+            options.groups = true;
+            let file =
+                File::create(&path).map_err(|err| PathError::wrap("creating", &path, err))?;
+            Some((path, file))
+        }
+        None => None,
+    };
+    let script = match script {
+        Some(path) => {
+            options.dry_run = true; // the script does the removing, once reviewed
+            options.groups = true;
+            let file =
+                create_script(&path).map_err(|err| PathError::wrap("creating", &path, err))?;
+            Some((path, file))
+        }
+        None => None,
+    };
+    let hashes = match hashes {
+        Some(path) => {
+            options.hashes = true;
+            options.groups |= hash_duplicates;
+            let file =
+                File::create(&path).map_err(|err| PathError::wrap("creating", &path, err))?;
+            Some((path, file))
+        }
+        None => None,
+    };
+    if let Some(path) = log {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| PathError::wrap("opening", &path, err))?;
+        _ = LOG.set(Mutex::new(file));
+        options.on_action = Some(log_action);
+    }
+    options.records = report == Report::Json;
+    if report == Report::Ndjson {
+        options.on_record = Some(print_record_event);
+        options.on_failure = Some(print_error_event);
+    }
+    options.groups |= tree;
+    if copies {
+        options.dry_run = true; // a census of every copy, so none may be removed
+        options.groups = true;
+    }
+    options.progress = report.progress();
+    if log::max_level() >= log::LevelFilter::Info {
+        options.progress = None; // the log lines would interleave with the progress line
+    } else if report == Report::Text {
+        options.on_dry_run = Some(print_match); // at info level the log reports matches already
+    }
+    if watch && (list.is_some() || similar.is_some() || command == Command::Hash) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--watch only dedups the files that appear in directories, it can't be combined with a list, --similar or hash",
+        ));
+    }
+    if options.by_name && (similar.is_some() || command == Command::Hash) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--by-name only tells copies apart by name, it can't be combined with --similar or hash",
+        ));
+    }
+    if watch && (options.sort.is_some() || options.count_first || options.shard_by_size) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--watch scans files as they come, it can't wait for every path to --sort, --count-first or --shard-by-size them",
+        ));
+    }
+    if hashed.is_some() {
+        if watch || similar.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--from-hashes only compares the hashes listed, it can't be combined with --watch or --similar",
+            ));
+        }
+        let roots = [&options.references, &options.protect, &options.delete_under];
+        if roots.iter().any(|roots| !roots.is_empty()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--from-hashes only compares the files listed, it can't be combined with --reference, --protect or --delete-under",
+            ));
+        }
+        if options.compare_bytes.is_some()
+            || options.sort.is_some()
+            || options.count_first
+            || options.shard_by_size
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--from-hashes takes whole-file hashes in the order listed, it can't be combined with --compare-bytes, --sort, --count-first or --shard-by-size",
+            ));
+        }
+    }
+    let hashed = hashed.as_deref().map(read_hashes).transpose()?;
+    let walk_errors;
+    let mut arrivals = None;
+    let paths: Box<dyn Iterator<Item = PathBuf> + Send> = match list {
+        _ if hashed.is_some() => {
+            walk_errors = Default::default();
+            Box::new(std::iter::empty())
+        }
+        Some(list) => {
+            let roots = (options.references.iter())
+                .chain(&options.protect)
+                .chain(&options.delete_under);
+            let walk = Walk::new(roots, recursive)?
+                .include_hidden(options.include_hidden)
+                .ignore_files(!no_ignore)
+                .skip_dirs(skip_dirs)
+                .threads(walk_threads)
+                .max_depth(max_depth)
+                .on_failure(options.on_failure);
+            walk_errors = walk.errors();
+            // Collected, so the map is sized by the line count.
+            Box::new(walk.chain(list.read()?))
+        }
+        None => {
+            let roots = (options.references.iter())
+                .chain(&options.protect)
+                .chain(&options.delete_under)
+                .map(PathBuf::as_path)
+                .chain(dirs.iter().map(Path::new));
+            // Watched before the walk, so nothing that appears during it is missed.
+            if watch {
+                arrivals = Some(watch::Arrivals::new(
+                    roots.clone(),
+                    recursive,
+                    &INTERRUPTED,
+                )?);
+            }
+            let walk = Walk::new(roots, recursive)?
+                .include_hidden(options.include_hidden)
+                .ignore_files(!no_ignore)
+                .skip_dirs(skip_dirs)
+                .threads(walk_threads)
+                .max_depth(max_depth)
+                .on_failure(options.on_failure);
+            walk_errors = walk.errors();
+            // Arrivals skip what the walk would have, e.g. what a --skip-dir or ignore file says to.
+            arrivals = arrivals.map(|arrivals| arrivals.admitting(walk.admits()));
+            Box::new(walk)
+        }
+    };
+
+    if let Some(threshold) = similar {
+        if command != Command::Scan {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--similar only reports similar files, it's only available with scan",
+            ));
+        }
+        let summary = find_similar(paths, threshold, &options);
+        print_similar(&summary);
+        Reporter::get().flush()?;
+        let errors = summary.errors + walk_errors.load(Relaxed);
+        return Ok(if errors > 0 {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        });
+    }
+
+    if command == Command::Hash {
+        let summary = hash_files(paths, &options)?;
+        for hashed in &summary.hashes {
+            report!("{}", checksum_line(hashed));
+        }
+        Reporter::get().flush()?;
+        let errors = summary.errors + walk_errors.load(Relaxed);
+        return Ok(if errors > 0 {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        });
+    }
+
+    // Stop between files rather than dying mid-action, which could leave a hard link half done.
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Relaxed)).map_err(Error::other)?;
+    options.stop = Some(&INTERRUPTED);
+
+    let result = match (arrivals, hashed) {
+        (Some(arrivals), _) => file_cleaner::watch(paths, arrivals, &options),
+        (None, Some(files)) => deduplicate_hashed(files.into_iter(), &options),
+        (None, None) => deduplicate(paths, &options),
+    };
+    if options.progress.is_some() {
+        eprintln!(); // end the progress line
+    }
+    let mut summary = result?;
+    summary.errors += walk_errors.load(Relaxed);
+
+    report.summary(&mut summary, &options, tree, copies);
+    Reporter::get().flush()?;
+    if let Some((path, file)) = manifest {
+        write_manifest(file, &summary).map_err(|err| PathError::wrap("writing", &path, err))?;
+    }
+    if let Some((path, file)) = script {
+        write_script(file, &summary).map_err(|err| PathError::wrap("writing", &path, err))?;
+    }
+    if let Some((path, file)) = hashes {
+        write_hashes(file, &summary).map_err(|err| PathError::wrap("writing", &path, err))?;
+    }
+    if INTERRUPTED.load(Relaxed) {
+        log::warn!("interrupted");
+        return Ok(ExitCode::from(EXIT_INTERRUPTED));
+    }
+    if summary.errors > 0 {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// How results are shown on stdout; errors go to stderr regardless.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Report {
+    /// Dry-run matches as they're found, then a one-line summary.
+    Text,
+    /// A single JSON document once done, with progress on stderr.
+    Json,
+    /// A JSON object per line as things happen: each duplicate acted upon, each error, then the summary.
+    Ndjson,
+    /// Nothing, the exit code tells how it went.
+    Quiet,
+}
+
+impl Report {
+    fn progress(self) -> Option<fn(usize, Option<usize>)> {
+        match self {
+            Report::Text | Report::Json | Report::Ndjson => Some(print_scanned),
+            Report::Quiet => None,
+        }
+    }
+
+    fn summary(self, summary: &mut Summary, options: &Options, tree: bool, copies: bool) {
+        match self {
+            Report::Text => {
+                let freed = if options.dry_run {
+                    "would free"
+                } else {
+                    "freed"
+                };
+                let percent = match summary.bytes_scanned {
+                    0 => String::new(),
+                    scanned => format!(" ({}%)", summary.bytes_freed * 100 / scanned),
+                };
+                report!(
+                    "found: {}, {}: {}, {freed}: {} of {} scanned{percent}, errors: {}",
+                    summary.found,
+                    options.action.label(options.dry_run),
+                    summary.deleted,
+                    human_size(summary.bytes_freed),
+                    human_size(summary.bytes_scanned),
+                    summary.errors,
+                );
+                print_extensions(summary);
+                if let Some(dir) = options.quarantine.as_ref().filter(|_| summary.deleted > 0) {
+                    report!("quarantined in {}", dir.display());
+                }
+                if summary.empty > 0 {
+                    let how = match options.empty {
+                        Empty::Dedup => "deduplicated",
+                        Empty::Skip | Empty::Report => "left alone",
+                    };
+                    report!("empty files: {} ({how})", summary.empty);
+                }
+                for path in &summary.empty_files {
+                    report!("{} is empty", path.display());
+                }
+                if summary.collapsed > 0 {
+                    report!("skipped {} paths given more than once", summary.collapsed);
+                }
+                if summary.changed > 0 {
+                    report!(
+                        "left {} duplicates alone as they changed during the scan",
+                        summary.changed
+                    );
+                }
+                if summary.other_ext > 0 {
+                    report!(
+                        "left {} duplicates alone as their copies have another extension",
+                        summary.other_ext
+                    );
+                }
+                if summary.zero_prefix > 0 {
+                    report!(
+                        "{} files started with zeros only, told apart by size too",
+                        summary.zero_prefix
+                    );
+                }
+                if summary.pruned_dirs > 0 {
+                    report!("pruned {} empty directories", summary.pruned_dirs);
+                }
+                if let Some(profile) = &summary.profile {
+                    let summed = match options.threads.get() {
+                        1 => String::new(),
+                        threads => format!(" (summed over {threads} threads)"),
+                    };
+                    report!(
+                        "time: {:.2?}; walking {:.2?}, reading IDs {:.2?}, comparing {:.2?}, acting {:.2?}{summed}",
+                        profile.total,
+                        profile.walk,
+                        profile.ids,
+                        profile.compare,
+                        profile.act,
+                    );
+                    report!(
+                        "read: {} for IDs, {} to compare, in {} reads",
+                        human_size(profile.id_bytes),
+                        human_size(profile.compare_bytes),
+                        profile.reads,
+                    );
+                }
+                if tree {
+                    print_tree(summary);
+                }
+                if copies {
+                    print_copies(summary);
+                }
+            }
+            Report::Json => report!("{}", json_report(summary, options)),
+            Report::Ndjson => {
+                let json = json_report(summary, options);
+                report!("{{\"event\":\"summary\",{}", &json[1..]);
+            }
+            Report::Quiet => {}
+        }
+    }
+}
+
+/// A destination for results, kept apart from progress and the log on stderr so the two can be redirected separately.
+struct Reporter {
+    out: Mutex<Box<dyn Write + Send>>,
+    /// Named in write errors.
+    name: String,
+}
+
+impl Reporter {
+    /// "stdout", "stderr", or a file to create.
+    fn open(dest: &Path) -> Result<Self, Error> {
+        let out: Box<dyn Write + Send> = match dest.to_str() {
+            Some("stdout" | "-") => Box::new(std::io::stdout()),
+            Some("stderr") => Box::new(std::io::stderr()),
+            _ => {
+                let file =
+                    File::create(dest).map_err(|err| PathError::wrap("creating", dest, err))?;
+                Box::new(std::io::BufWriter::new(file))
+            }
+        };
+        Ok(Self {
+            out: Mutex::new(out),
+            name: dest.display().to_string(),
+        })
+    }
+
+    fn get() -> &'static Self {
+        REPORTER.get_or_init(|| Self::open(Path::new("stdout")).expect("stdout needs no opening"))
+    }
+
+    /// Write errors surface in [`Reporter::flush`], for the rest of the run's output to still be written.
+    fn line(&self, args: std::fmt::Arguments) {
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        _ = out.write_fmt(args).and_then(|()| out.write_all(b"\n"));
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        out.flush()
+            .map_err(|err| PathError::wrap("writing", Path::new(&self.name), err))
+    }
+}
+
+/// A list of paths to scan instead of walking directories.
+enum List {
+    File(PathBuf),
+    Stdin,
+}
+
+impl List {
+    /// Reads one path per line, skipping empty lines.
+    /// Paths are checked when they're scanned, so missing ones are reported and counted as errors there.
+    fn read(self) -> Result<Vec<PathBuf>, Error> {
+        use std::io::{BufRead, BufReader};
 
-static mut DELETED: usize = 0; // as long as program-local threading is used, any read/write should be safe
-static mut SCANNED: usize = 0; // as long as program-local threading is used, any read/write should be safe
-                               // I'm relatively certain we shouldn't have to worry about hardware reordering within 1 program.
-                               // It's possible any form of concurrency is safe,
-                               // as long as the threads only deal with 1 version of the variable (as in, there's no seperate value for each thread.)
-                               // Beware of cache coherency issues when not using program-local threading.
+        let (lines, name) = match self {
+            List::File(path) => {
+                let file =
+                    File::open(&path).map_err(|err| PathError::wrap("opening", &path, err))?;
+                let name = path.display().to_string();
+                (Box::new(BufReader::new(file)) as Box<dyn BufRead>, name)
+            }
+            List::Stdin => (Box::new(std::io::stdin().lock()) as _, "stdin".to_owned()),
+        };
+        lines
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(String::is_empty))
+            .map(|line| line.map(PathBuf::from))
+            .collect::<Result<_, _>>()
+            .map_err(|err| Error::new(err.kind(), format!("{name}: {err}")))
+    }
+}
+
+/// Reads `<hash>\t<path>` lines for --from-hashes, skipping empty ones; hashes are in hex, as `hash` prints them.
+/// Paths are checked when they're scanned, as with [`List::read`].
+fn read_hashes(path: &Path) -> Result<Vec<([u8; 32], PathBuf)>, Error> {
+    use std::io::{BufRead, BufReader};
+
+    let file = File::open(path).map_err(|err| PathError::wrap("opening", path, err))?;
+    let mut files = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| PathError::wrap("reading", path, err))?;
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.split_once('\t').and_then(|(hex, file)| {
+            let mut hash = [0u8; 32];
+            if hex.len() != 2 * hash.len() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+                *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+            }
+            Some((hash, PathBuf::from(file)))
+        });
+        let Some(parsed) = parsed else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{}:{}: expected a 64-digit hex hash, a tab and a path",
+                    path.display(),
+                    n + 1
+                ),
+            ));
+        };
+        files.push(parsed);
+    }
+    Ok(files)
+}
 
-type ID = usize;
-const IDSIZE: usize = size_of::<ID>();
+/// Logs warnings and errors to stderr, only errors with --quiet, or everything down to `debug` with --verbose;
+/// `RUST_LOG` overrides any of these.
+/// Messages are printed as is, with only warnings and errors prefixed by their level.
+fn init_logger(verbose: bool, report: Report) {
+    use log::{Level, LevelFilter};
+    use std::io::Write;
 
-static mut MAP: MaybeUninit<HashMap<ID, Vec<PathBuf>>> = MaybeUninit::uninit();
+    let level = match (verbose, report) {
+        (true, _) => LevelFilter::Debug,
+        (false, Report::Quiet) => LevelFilter::Error,
+        (false, _) => LevelFilter::Warn,
+    };
+    env_logger::Builder::new()
+        .filter_level(level.min(LevelFilter::Warn)) // --verbose is about our files, not the dependencies' internals
+        .filter_module(env!("CARGO_CRATE_NAME"), level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Error => writeln!(buf, "error: {}", record.args()),
+            Level::Warn => writeln!(buf, "warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+}
 
-macro_rules! retry_interrupts {
-    ($e:expr) => {
+/// Appends a JSON line for `dup` to [`LOG`], before it's acted upon so a crash mid-action still leaves a trace.
+/// A path that isn't valid Unicode can't be written to JSON as it is, so `dup` is left alone rather than logged under another name.
+fn log_action(action: Action, dup: &Path, original: &Path) -> Result<(), Error> {
+    use std::io::Write;
+
+    let json_path = |path: &Path| {
+        path.to_str().map(json_str).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "left in place, {} isn't valid Unicode so restore couldn't find it in the log",
+                    path.display()
+                ),
+            )
+        })
+    };
+    let line = format!(
+        "{{\"action\":\"{}\",\"path\":{},\"original\":{}}}\n",
+        action.name(),
+        json_path(dup)?,
+        json_path(original)?,
+    );
+    let log = LOG.get().expect("set along with on_action");
+    // One write per line, so concurrent workers can't interleave within a line.
+    log.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .write_all(line.as_bytes())
+        .map_err(|err| Error::new(err.kind(), format!("failed to write the log: {err}")))
+}
+
+/// Undoes the runs logged to `path` by copying every original back to its duplicate's path.
+/// Paths that exist already, such as hard links, are left alone.
+fn restore_from(path: &Path) -> Result<ExitCode, Error> {
+    use std::io::{BufRead, BufReader};
+
+    let file = File::open(path).map_err(|err| PathError::wrap("opening", path, err))?;
+    let mut restored = 0;
+    let mut errors = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| PathError::wrap("reading", path, err))?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry = parse_json_strings(&line);
+        let (Some(dup), Some(original)) = (
+            entry.as_ref().and_then(|e| e.get("path")),
+            entry.as_ref().and_then(|e| e.get("original")),
+        ) else {
+            log::error!("{}:{}: not a log entry", path.display(), i + 1);
+            errors += 1;
+            continue;
+        };
+        let dup = Path::new(dup);
+        if dup.symlink_metadata().is_ok() {
+            continue;
+        }
+        let copied = match dup.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+            _ => Ok(()),
+        }
+        .and_then(|()| std::fs::copy(original, dup));
+        match copied {
+            Ok(_) => restored += 1,
+            Err(err) => {
+                log::error!("{}: {err}", dup.display());
+                errors += 1;
+            }
+        }
+    }
+    println!("restored: {restored}, errors: {errors}");
+    Ok(if errors > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Parses a flat JSON object of string values, as written by [`log_action`]; `None` if it's anything else.
+fn parse_json_strings(s: &str) -> Option<std::collections::HashMap<String, String>> {
+    let mut chars = s.trim().chars().peekable();
+    let mut fields = std::collections::HashMap::new();
+    let skip_ws = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    let parse_str = |chars: &mut std::iter::Peekable<std::str::Chars>| -> Option<String> {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let mut out = String::new();
         loop {
-            match $e {
-                Ok(x) => break Ok(x),
-                Err(err) => match err.kind() {
-                    ErrorKind::Interrupted => continue, // Try $e again, if error == interrupted
-                    _ => break Err(err),
+            match chars.next()? {
+                '"' => return Some(out),
+                '\\' => match chars.next()? {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    c => out.push(c), // \" \\ and \/
                 },
+                c => out.push(c),
             }
         }
     };
-}
-fn main() -> Result<(), Error> {
-    let dir = std::env::args_os().nth(1).unwrap_or_default();
-    let paths = read_dir(dir)?.flatten().map(|x| x.path());
 
-    unsafe {
-        // initialize MAP before asynchronous code
-        MAP = MaybeUninit::new(map_from_iter(&paths));
+    skip_ws(&mut chars);
+    if chars.next()? != '{' {
+        return None;
+    }
+    loop {
+        skip_ws(&mut chars);
+        let key = parse_str(&mut chars)?;
+        skip_ws(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_ws(&mut chars);
+        let value = parse_str(&mut chars)?;
+        fields.insert(key, value);
+        skip_ws(&mut chars);
+        match chars.next()? {
+            ',' => {}
+            '}' => return chars.next().is_none().then_some(fields),
+            _ => return None,
+        }
     }
-    remove_duplicates(paths);
+}
 
-    Ok(())
+/// Parses the value following `flag`.
+fn parse_value<T: std::str::FromStr>(
+    args: &mut impl Iterator<Item = std::ffi::OsString>,
+    flag: &str,
+) -> Result<T, Error> {
+    let value = args
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{flag} expects a value")))?;
+    value.to_str().and_then(|v| v.parse().ok()).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value for {flag}: {}", value.to_string_lossy()),
+        )
+    })
 }
 
-fn remove_duplicates(paths: impl Iterator<Item = PathBuf>) {
-    unsafe {
-        print_scanned(SCANNED); // prints 0 for when no files were found
+/// A byte count like "500", "500K", "1.5MiB" or "2GB".
+/// K, M, G and T are binary, same as KiB, MiB, GiB and TiB; KB, MB, GB and TB are decimal. Case is ignored.
+struct Size(u64);
+
+impl std::str::FromStr for Size {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let multiplier: u64 = match unit.trim_start().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kib" => 1 << 10,
+            "m" | "mib" => 1 << 20,
+            "g" | "gib" => 1 << 30,
+            "t" | "tib" => 1 << 40,
+            "kb" => 1_000,
+            "mb" => 1_000_000,
+            "gb" => 1_000_000_000,
+            "tb" => 1_000_000_000_000,
+            _ => return Err(()),
+        };
+        if let Ok(whole) = number.parse::<u64>() {
+            return whole.checked_mul(multiplier).map(Size).ok_or(());
+        }
+        match number.parse::<f64>() {
+            Ok(fraction) if fraction.is_finite() => {
+                let bytes = fraction * multiplier as f64;
+                if bytes < u64::MAX as f64 {
+                    Ok(Size(bytes as u64))
+                } else {
+                    Err(())
+                }
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+/// A point in time, either as an age like "30d", "12h", "2w" or "90s", or a UTC date like "2024-05-01" or "2024-05-01T13:30:00".
+struct Time(SystemTime);
+
+impl std::str::FromStr for Time {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let s = s.trim();
+        if let Some(split) = s.find(|c: char| !c.is_ascii_digit()).filter(|&i| i > 0) {
+            let (number, unit) = s.split_at(split);
+            let seconds: u64 = match unit {
+                "s" => 1,
+                "m" => 60,
+                "h" => 60 * 60,
+                "d" => 24 * 60 * 60,
+                "w" => 7 * 24 * 60 * 60,
+                _ => return Self::from_date(s),
+            };
+            let age = number
+                .parse::<u64>()
+                .map_err(|_| ())?
+                .checked_mul(seconds)
+                .ok_or(())?;
+            return SystemTime::now()
+                .checked_sub(Duration::from_secs(age))
+                .map(Time)
+                .ok_or(());
+        }
+        Self::from_date(s)
     }
-    for cur_path in paths {
-        fn inner(cur_path: PathBuf) {
-            scan_file(cur_path);
-            unsafe {
-                SCANNED += 1;
-                print_scanned(SCANNED);
+}
+
+impl Time {
+    fn from_date(s: &str) -> Result<Self, ()> {
+        let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, "00:00:00"));
+        let number = |field: &str, max: u32| match field.parse::<u32>() {
+            Ok(n) if n <= max && !field.is_empty() && field.bytes().all(|b| b.is_ascii_digit()) => {
+                Ok(n)
             }
+            _ => Err(()),
+        };
+        let [year, month, day] = split3(date, '-').ok_or(())?;
+        let (year, month, day) = (number(year, 9999)?, number(month, 12)?, number(day, 31)?);
+        let time = match time.matches(':').count() {
+            1 => format!("{time}:00"),
+            _ => time.to_owned(),
+        };
+        let [hour, minute, second] = split3(&time, ':').ok_or(())?;
+        let (hour, minute, second) = (number(hour, 23)?, number(minute, 59)?, number(second, 59)?);
+        if month == 0 || day == 0 || day > days_in_month(year, month) {
+            return Err(());
+        }
+
+        // Days since 1970-01-01, counting in years that start in March so the leap day comes last.
+        let (y, m) = if month <= 2 {
+            (year as i64 - 1, month + 9)
+        } else {
+            (year as i64, month - 3)
+        };
+        let era = y.div_euclid(400);
+        let year_of_era = y - era * 400;
+        let day_of_year = (153 * m as i64 + 2) / 5 + day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = days * 86_400 + (hour * 3600 + minute * 60 + second) as i64;
+
+        let offset = Duration::from_secs(seconds.unsigned_abs());
+        let time = if seconds >= 0 {
+            SystemTime::UNIX_EPOCH.checked_add(offset)
+        } else {
+            SystemTime::UNIX_EPOCH.checked_sub(offset)
+        };
+        time.map(Time).ok_or(())
+    }
+}
+
+fn split3(s: &str, separator: char) -> Option<[&str; 3]> {
+    let mut fields = s.split(separator);
+    let fields = [fields.next()?, fields.next()?, fields.next()?];
+    s.matches(separator).count().eq(&2).then_some(fields)
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
         }
-        inner(cur_path);
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
     }
+}
 
-    unsafe {
-        println!("\ndeleted: {DELETED}");
+/// Formats `bytes` with binary units, e.g. "1.4 GiB".
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
+    format!("{size:.1} {}", UNITS[unit])
 }
 
-fn print_scanned(num: usize) {
-    print!("\rscanned: {num}");
+fn print_match(dup: &Path, original: &Path) {
+    report!("{} is a duplicate of {}", dup.display(), original.display());
 }
 
-fn map_from_iter<K, V>(iter: &impl Iterator) -> HashMap<K, V> {
-    use nohash_hasher::BuildNoHashHasher;
-    use std::collections::HashMap;
+/// Progress goes to stderr along with the log, apart from the [`REPORTER`].
+fn print_scanned(num: usize, total: Option<usize>) {
+    eprint!("\r{}", progress_line(num, total));
+}
+
+/// "scanned: 1234", or "scanned: 1234 / 50000 (2%)" once the total is known.
+fn progress_line(num: usize, total: Option<usize>) -> String {
+    match total {
+        Some(0) | None => format!("scanned: {num}"),
+        Some(total) => format!("scanned: {num} / {total} ({}%)", num * 100 / total),
+    }
+}
 
-    fn inner(size_hint: (usize, Option<usize>)) -> usize {
-        match size_hint.1 {
-            Some(size) => size,  // return upperbound hint
-            None => size_hint.0, // return lowerbound hint
+/// Asks on stdin whether to act on `dup`; EOF or an unreadable answer counts as no.
+fn prompt(action: Action, dup: &Path, original: &Path) -> Confirm {
+    use std::io::{stdin, stdout, Write};
+
+    let mut line = String::new();
+    loop {
+        print!(
+            "\n{} is a duplicate of {}\n{}? [y]es/[n]o/[a]ll/[q]uit: ",
+            dup.display(),
+            original.display(),
+            action.verb(),
+        );
+        _ = stdout().flush();
+
+        line.clear();
+        match stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => return Confirm::No,
+            Ok(_) => {}
+        }
+        match line.trim() {
+            "y" | "yes" => return Confirm::Yes,
+            "n" | "no" => return Confirm::No,
+            "a" | "all" => return Confirm::All,
+            "q" | "quit" => return Confirm::Quit,
+            _ => {} // ask again
         }
     }
-    let size_hint = iter.size_hint();
-    let capacity = inner(size_hint);
+}
 
-    HashMap::with_capacity_and_hasher(capacity, BuildNoHashHasher::default())
+/// A `deleted` line for --ndjson, whatever the action; the summary line tells whether it was a dry run.
+fn print_record_event(record: &Record) {
+    report!(
+        "{{\"event\":\"deleted\",\"path\":{},\"dup_of\":{},\"size\":{},\"id\":\"{:016x}\"}}",
+        json_str(&record.path.to_string_lossy()),
+        json_str(&record.original.to_string_lossy()),
+        record.size,
+        record.id,
+    );
 }
 
-fn read_id(path: &Path) -> Result<ID, Error> {
-    use std::{fs::File, io::Read};
+/// An `error` line for --ndjson.
+fn print_error_event(path: &Path, err: &Error) {
+    report!(
+        "{{\"event\":\"error\",\"path\":{},\"error\":{}}}",
+        json_str(&path.to_string_lossy()),
+        json_str(&err.to_string()),
+    );
+}
 
-    let mut buf = [0u8; IDSIZE];
+fn json_report(summary: &Summary, options: &Options) -> String {
+    use std::fmt::Write;
 
-    match retry_interrupts!(File::open(path))?.read_exact(&mut buf) {
-        Ok(()) => Ok(ID::from_ne_bytes(buf)),
-        Err(e) => Err(e),
+    let mut out = format!(
+        "{{\"action\":\"{}\",\"dry_run\":{},\"scanned\":{},\"found\":{},\"collapsed\":{},\"changed\":{},\"other_ext\":{},\"zero_prefix\":{},\"ruled_out\":{},\"deleted_count\":{},\"bytes_freed\":{},\"bytes_scanned\":{},\"errors\":{},\"empty\":{},\"pruned_dirs\":{},\"deleted\":[",
+        options.action.name(),
+        options.dry_run,
+        summary.scanned,
+        summary.found,
+        summary.collapsed,
+        summary.changed,
+        summary.other_ext,
+        summary.zero_prefix,
+        summary.ruled_out,
+        summary.deleted,
+        summary.bytes_freed,
+        summary.bytes_scanned,
+        summary.errors,
+        summary.empty,
+        summary.pruned_dirs,
+    );
+    for (i, record) in summary.records.iter().enumerate() {
+        if i != 0 {
+            out.push(',');
+        }
+        _ = write!(
+            out,
+            "{{\"path\":{},\"original\":{},\"size\":{},\"id\":\"{:016x}\"}}",
+            json_str(&record.path.to_string_lossy()),
+            json_str(&record.original.to_string_lossy()),
+            record.size,
+            record.id,
+        );
+    }
+    out.push_str("],\"extensions\":{");
+    for (i, (ext, stats)) in summary.extensions.iter().enumerate() {
+        if i != 0 {
+            out.push(',');
+        }
+        _ = write!(
+            out,
+            "{}:{{\"deleted\":{},\"bytes_freed\":{}}}",
+            json_str(ext),
+            stats.deleted,
+            stats.bytes_freed,
+        );
     }
+    out.push('}');
+    if let Some(profile) = &summary.profile {
+        _ = write!(
+            out,
+            ",\"profile\":{{\"walk\":{},\"ids\":{},\"compare\":{},\"act\":{},\"total\":{},\"id_bytes\":{},\"compare_bytes\":{},\"reads\":{}}}",
+            profile.walk.as_secs_f64(),
+            profile.ids.as_secs_f64(),
+            profile.compare.as_secs_f64(),
+            profile.act.as_secs_f64(),
+            profile.total.as_secs_f64(),
+            profile.id_bytes,
+            profile.compare_bytes,
+            profile.reads,
+        );
+    }
+    if let Some(dir) = &options.quarantine {
+        _ = write!(out, ",\"quarantine\":{}", json_str(&dir.to_string_lossy()));
+    }
+    if options.empty == Empty::Report {
+        out.push_str(",\"empty_files\":[");
+        for (i, path) in summary.empty_files.iter().enumerate() {
+            if i != 0 {
+                out.push(',');
+            }
+            out.push_str(&json_str(&path.to_string_lossy()));
+        }
+        out.push(']');
+    }
+    out.push('}');
+    out
 }
 
-fn scan_file(cur_path: PathBuf) {
-    let Ok(id) = read_id(&cur_path) else {
-        return;
-    };
+/// Prints every group of similar files, with its size, then a summary line.
+fn print_similar(summary: &SimilarSummary) {
+    for group in &summary.groups {
+        report!("similar ({:.0}%):", group.similarity * 100.0);
+        for (path, size) in &group.files {
+            report!("  {} ({})", path.display(), human_size(*size));
+        }
+    }
+    report!(
+        "scanned: {}, similar groups: {}, errors: {}",
+        summary.scanned,
+        summary.groups.len(),
+        summary.errors,
+    );
+}
 
-    let Some(paths) = unsafe { MAP.assume_init_mut() }.get_mut(&id) else {
-        unsafe { MAP.assume_init_mut() }.insert(id, vec![cur_path]);
-        return;
-    };
+/// A share from 0 to 1, written either like "0.8" or "80%".
+struct Percent(f64);
 
-    let Ok(data) = read(&cur_path) else {
-        return;
-    };
+impl std::str::FromStr for Percent {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let share = match s.trim().strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f64>().map_err(|_| ())? / 100.0,
+            None => s.trim().parse().map_err(|_| ())?,
+        };
+        if (0.0..=1.0).contains(&share) {
+            Ok(Percent(share))
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// Prints how two files that start alike differ, with the bytes around the first difference in hex.
+fn print_mismatch(a: &Path, b: &Path, mismatch: &Mismatch) {
+    match mismatch {
+        Mismatch::Size(a_size, b_size) => report!(
+            "{} ({a_size} bytes) and {} ({b_size} bytes) start alike but differ in size",
+            a.display(),
+            b.display(),
+        ),
+        Mismatch::Bytes {
+            offset,
+            start,
+            a: a_bytes,
+            b: b_bytes,
+        } => {
+            report!(
+                "{} and {} differ at byte {offset}:",
+                a.display(),
+                b.display()
+            );
+            let hex = |bytes: &[u8]| {
+                let hex: Vec<_> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+                hex.join(" ")
+            };
+            let marker = " ".repeat((offset - start) as usize * 3);
+            report!("  {start:>10}: {}", hex(a_bytes));
+            report!("  {start:>10}: {}", hex(b_bytes));
+            report!("  {:>10}  {marker}^^", "");
+        }
+    }
+}
+
+/// Prints what was acted upon per extension, most bytes freed first.
+fn print_extensions(summary: &Summary) {
+    let mut extensions: Vec<_> = summary.extensions.iter().collect();
+    extensions.sort_by(|a, b| b.1.bytes_freed.cmp(&a.1.bytes_freed).then(a.0.cmp(b.0)));
+    let width = extensions.iter().map(|(ext, _)| ext_label(ext).len()).max();
+    for (ext, stats) in extensions {
+        report!(
+            "  {:>width$}: {} duplicates, {}",
+            ext_label(ext),
+            stats.deleted,
+            human_size(stats.bytes_freed),
+            width = width.unwrap_or(0),
+        );
+    }
+}
+
+fn ext_label(ext: &str) -> &str {
+    if ext.is_empty() {
+        "(none)"
+    } else {
+        ext
+    }
+}
 
-    for old_path in &*paths {
-        // Invalidates other iters if async:
-        // if we exit the loop, another thread could lengthen the list (not the iter); meaning this thread would no longer check all unique files with the same ID.
-        // The recently added file could be a duplicate, if it is:
-        // this thread will now add this file as another unique file, thusly leaving us with 2 identical files in the list of unique files.
-        let Ok(other) = read(old_path) else { continue };
-
-        if other == data {
-            // should be async safe, as no edits are being made to MAP after.
-            match retry_interrupts!(remove_file(&cur_path)) {
-                Ok(()) => unsafe { DELETED += 1 },
-                Err(err) => eprintln!("{err}"),
+/// Prints every kept file with the duplicates acted upon under it, sorted by path.
+fn print_tree(summary: &mut Summary) {
+    summary.groups.sort_by(|a, b| a.keeper.cmp(&b.keeper));
+    for group in &summary.groups {
+        report!("{} ({})", group.keeper.display(), human_size(group.size));
+        for (i, dup) in group.duplicates.iter().enumerate() {
+            let branch = if i + 1 == group.duplicates.len() {
+                "└─"
+            } else {
+                "├─"
             };
-            drop(cur_path); // cur_path is either removed or in an invalid state; drop it to make sure it can't be used.
-            return;
+            report!("  {branch} {}", dup.display());
+        }
+    }
+}
+
+/// Every group of identical files with its copy count, most bytes reclaimable first.
+fn print_copies(summary: &mut Summary) {
+    let wasted = |group: &Group| group.size * group.duplicates.len() as u64;
+    summary
+        .groups
+        .sort_by(|a, b| (wasted(b).cmp(&wasted(a))).then_with(|| a.keeper.cmp(&b.keeper)));
+    for group in &summary.groups {
+        report!(
+            "{} copies of {}, {} reclaimable:",
+            group.duplicates.len() + 1,
+            human_size(group.size),
+            human_size(wasted(group)),
+        );
+        for path in std::iter::once(&group.keeper).chain(&group.duplicates) {
+            report!("  {}", path.display());
+        }
+    }
+}
+
+/// Writes one CSV row per file of every group of identical files.
+fn write_manifest(file: File, summary: &Summary) -> Result<(), Error> {
+    use std::io::{BufWriter, Write};
+
+    let mut out = BufWriter::new(file);
+    writeln!(out, "group,path,size,keeper")?;
+    for (i, group) in summary.groups.iter().enumerate() {
+        let keeper = std::iter::once((&group.keeper, true));
+        let duplicates = group.duplicates.iter().map(|path| (path, false));
+        for (path, is_keeper) in keeper.chain(duplicates) {
+            let path = path.to_string_lossy();
+            writeln!(out, "{i},{},{},{is_keeper}", csv_field(&path), group.size)?;
+        }
+    }
+    out.flush()
+}
+
+/// Writes a `<hex digest>\t<size>\t<path>` line per kept file, and per duplicate with --emit-duplicates.
+fn write_hashes(file: File, summary: &Summary) -> Result<(), Error> {
+    use std::io::{BufWriter, Write};
+
+    let mut out = BufWriter::new(file);
+    for hashed in &summary.hashes {
+        let hex: String = hashed
+            .hash
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        for path in std::iter::once(&hashed.path).chain(&hashed.duplicates) {
+            writeln!(out, "{hex}\t{}\t{}", hashed.size, path.display())?;
+        }
+    }
+    out.flush()
+}
+
+/// A `<hex digest>  <path>` line as `sha256sum` or `b3sum` print it, so either can `--check` it:
+/// a path with a backslash or newline has those escaped, and the line starts with a backslash to say so.
+fn checksum_line(hashed: &Hashed) -> String {
+    let hex: String = hashed
+        .hash
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let path = hashed.path.to_string_lossy();
+    if path.contains(['\\', '\n']) {
+        let path = path.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{hex}  {path}")
+    } else {
+        format!("{hex}  {path}")
+    }
+}
+
+/// Creates `path` for --emit-script, executable by whoever may read it on Unix.
+fn create_script(path: &Path) -> Result<File, Error> {
+    let mut open = File::options();
+    open.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o755);
+    open.open(path)
+}
+
+/// Writes a script removing the duplicates of every group, POSIX sh or PowerShell on Windows, for --emit-script.
+/// Each group starts with a comment naming the copy kept. Paths are written as found, so relative ones need the same working directory.
+fn write_script(file: File, summary: &Summary) -> Result<(), Error> {
+    use std::io::{BufWriter, Write};
+
+    let mut out = BufWriter::new(file);
+    if !cfg!(windows) {
+        writeln!(out, "#!/bin/sh")?;
+    }
+    writeln!(
+        out,
+        "# Review before running: each line removes a duplicate."
+    )?;
+    let mut groups: Vec<_> = summary.groups.iter().collect();
+    groups.sort_by(|a, b| a.keeper.cmp(&b.keeper));
+    for group in groups {
+        // Debug-escaped, a newline in the name mustn't end the comment.
+        let keeper = group.keeper.to_string_lossy();
+        writeln!(out, "\n# keep {}", keeper.escape_debug())?;
+        for dup in &group.duplicates {
+            if cfg!(windows) {
+                writeln!(
+                    out,
+                    "Remove-Item -LiteralPath {}",
+                    ps_quote(&dup.to_string_lossy())
+                )?;
+            } else {
+                out.write_all(b"rm -- ")?;
+                out.write_all(&sh_quote(dup))?;
+                out.write_all(b"\n")?;
+            }
+        }
+    }
+    out.flush()
+}
+
+/// `path` in single quotes for sh, byte for byte: only a quote means anything in there, and is closed, escaped and reopened.
+fn sh_quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in path.as_os_str().as_encoded_bytes() {
+        match byte {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            byte => quoted.push(byte),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// `s` in single quotes for PowerShell, which also takes the typographic single quotes as quotes: each is doubled.
+fn ps_quote(s: &str) -> String {
+    let mut quoted = String::from("'");
+    for c in s.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}') {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Quotes `s` as a CSV field if it contains anything that would break the row.
+fn csv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string.
+fn json_str(s: &str) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => _ = write!(out, "\\u{:04x}", c as u32),
+            c => out.push(c),
         }
     }
-    paths.push(cur_path);
+    out.push('"');
+    out
 }
@@ -0,0 +1,243 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Near-duplicate detection, see [`find_similar`]. Only ever reports, nothing is acted upon.
+
+use crate::{is_candidate, lock, quarantined, read_full, Options, BUFSIZE};
+use nohash_hasher::IntMap as HashMap;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+
+/// Chunk hashes kept per file; the estimate's error shrinks with the square root of this.
+const SKETCH: usize = 128;
+
+/// Chunks end where the rolling hash has these bits clear, for 64 bytes per chunk on average: about a line of a log.
+const BOUNDARY: u64 = 0xfc00_0000_0000_0000;
+
+/// Chunks are at least this long, so runs of one repeated byte don't make a boundary at every byte.
+const MIN_CHUNK: usize = 8;
+
+/// Files sharing a chunk hash are only compared with this many of the next files sharing it,
+/// so a chunk most files have, like a common header, costs linear rather than quadratic time.
+const NEIGHBOURS: usize = 64;
+
+/// Random values for the rolling hash, one per byte value, from splitmix64.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Files whose contents are at least [`find_similar`]'s threshold alike, each with its size.
+#[derive(Debug)]
+pub struct SimilarGroup {
+    /// Lowest estimated similarity between two files that put them in this group, from 0 to 1.
+    /// Groups are transitive, so two files in a large group may be less alike than this.
+    pub similarity: f64,
+    pub files: Vec<(PathBuf, u64)>,
+}
+
+#[derive(Debug, Default)]
+pub struct SimilarSummary {
+    pub scanned: usize,
+    /// Files that couldn't be read.
+    pub errors: usize,
+    /// Sorted by the first path of each, which are sorted themselves.
+    pub groups: Vec<SimilarGroup>,
+}
+
+/// Groups files among `paths` whose contents are at least `threshold` alike, from 0 to 1, without acting on any of them.
+///
+/// Each file is cut into chunks where a rolling hash of its bytes hits a boundary, so an insertion only changes the chunks around it,
+/// and similarity is the share of distinct chunks two files have in common, estimated from the [`SKETCH`] smallest chunk hashes of each.
+/// Identical files are 100% alike. Empty files are left out.
+/// Files are only compared with the [`NEIGHBOURS`] next to them among those sharing each chunk: alike files share many chunks, so they
+/// still meet through rarer ones, but two files whose only common chunks are each in more than that many files in between are missed.
+/// `paths` are filtered as for [`crate::deduplicate`]; only [`Options::threads`] and [`Options::on_failure`] are used besides the filters.
+pub fn find_similar(
+    paths: impl Iterator<Item = PathBuf> + Send,
+    threshold: f64,
+    options: &Options,
+) -> SimilarSummary {
+    let quarantine = (options.quarantine.as_ref())
+        .map(|dir| std::fs::canonicalize(dir).unwrap_or_else(|_| dir.clone()));
+    let queue =
+        Mutex::new(paths.filter(|path| {
+            is_candidate(path, options) && !quarantined(quarantine.as_deref(), path)
+        }));
+    let sketches = Mutex::new(Vec::new());
+    let errors = Mutex::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..options.threads.get() {
+            scope.spawn(|| loop {
+                let Some(path) = lock(&queue).next() else {
+                    break;
+                };
+                match sketch(&path) {
+                    Ok((0, _)) => {}
+                    Ok((size, sketch)) => lock(&sketches).push((path, size, sketch)),
+                    Err(err) => {
+                        log::error!("{}: {err}", path.display());
+                        *lock(&errors) += 1;
+                        if let Some(on_failure) = options.on_failure {
+                            on_failure(&path, &err);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    let mut sketches = sketches
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner);
+    sketches.sort_by(|a, b| a.0.cmp(&b.0));
+    let errors = errors.into_inner().unwrap_or_else(PoisonError::into_inner);
+
+    // Files sharing no chunk hash at all can't be alike, so only those sharing one are compared.
+    let mut postings: HashMap<u64, Vec<usize>> = HashMap::default();
+    for (i, (_, _, sketch)) in sketches.iter().enumerate() {
+        for &hash in sketch {
+            postings.entry(hash).or_default().push(i);
+        }
+    }
+    let mut sets = DisjointSets::new(sketches.len());
+    for files in postings.values() {
+        for (n, &i) in files.iter().enumerate() {
+            for &j in files[n + 1..].iter().take(NEIGHBOURS) {
+                if sets.find(i) == sets.find(j) {
+                    continue; // already grouped, through this pair or others
+                }
+                let similarity = estimate(&sketches[i].2, &sketches[j].2);
+                if similarity >= threshold {
+                    sets.union(i, j, similarity);
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<u64, SimilarGroup> = HashMap::default();
+    for (i, (path, size, _)) in sketches.iter().enumerate() {
+        let root = sets.find(i);
+        if sets.size[root] < 2 {
+            continue;
+        }
+        let group = groups.entry(root as u64).or_insert_with(|| SimilarGroup {
+            similarity: sets.similarity[root],
+            files: Vec::new(),
+        });
+        group.files.push((path.clone(), *size));
+    }
+    let mut groups: Vec<_> = groups.into_values().collect();
+    groups.sort_by(|a, b| a.files[0].0.cmp(&b.files[0].0));
+    SimilarSummary {
+        scanned: sketches.len(),
+        errors,
+        groups,
+    }
+}
+
+/// Reads `path` in chunks, returning its size and the smallest [`SKETCH`] distinct chunk hashes, sorted.
+fn sketch(path: &std::path::Path) -> Result<(u64, Vec<u64>), Error> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; BUFSIZE];
+    let mut smallest = BinaryHeap::with_capacity(SKETCH + 1);
+    let mut keep = |hash: u64| {
+        let small = smallest.len() < SKETCH || smallest.peek().is_some_and(|&max| hash < max);
+        if small && !smallest.iter().any(|&kept| kept == hash) {
+            smallest.push(hash);
+            if smallest.len() > SKETCH {
+                smallest.pop();
+            }
+        }
+    };
+    let mut chunk = xxhash_rust::xxh3::Xxh3::new();
+    let (mut rolling, mut chunk_len, mut size) = (0u64, 0, 0);
+    loop {
+        let len = read_full(&mut file, &mut buf)?;
+        let mut start = 0;
+        for (i, &byte) in buf[..len].iter().enumerate() {
+            rolling = (rolling << 1).wrapping_add(GEAR[byte as usize]);
+            chunk_len += 1;
+            if chunk_len >= MIN_CHUNK && rolling & BOUNDARY == 0 {
+                chunk.update(&buf[start..=i]);
+                keep(chunk.digest());
+                chunk.reset();
+                (start, chunk_len) = (i + 1, 0);
+            }
+        }
+        chunk.update(&buf[start..len]);
+        size += len as u64;
+        if len < BUFSIZE {
+            break;
+        }
+    }
+    if chunk_len > 0 {
+        keep(chunk.digest());
+    }
+    Ok((size, smallest.into_sorted_vec()))
+}
+
+/// Share of the smallest hashes of both sketches together that are in both, which estimates the share of chunks they have in common.
+fn estimate(a: &[u64], b: &[u64]) -> f64 {
+    let (mut i, mut j, mut union, mut both) = (0, 0, 0, 0);
+    while union < SKETCH && (i < a.len() || j < b.len()) {
+        match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) if x == y => (i, j, both) = (i + 1, j + 1, both + 1),
+            (Some(x), Some(y)) if x < y => i += 1,
+            (Some(_), None) => i += 1,
+            _ => j += 1,
+        }
+        union += 1;
+    }
+    both as f64 / union as f64
+}
+
+/// Union-find over file indices, tracking each set's size and lowest similarity that joined it.
+struct DisjointSets {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+    similarity: Vec<f64>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+            size: vec![1; len],
+            similarity: vec![1.0; len],
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]]; // halve the path on the way
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn union(&mut self, i: usize, j: usize, similarity: f64) {
+        let (mut i, mut j) = (self.find(i), self.find(j));
+        if self.size[i] < self.size[j] {
+            std::mem::swap(&mut i, &mut j);
+        }
+        self.parent[j] = i;
+        self.size[i] += self.size[j];
+        self.similarity[i] = self.similarity[i].min(self.similarity[j]).min(similarity);
+    }
+}
@@ -0,0 +1,120 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Files that appear under the scanned directories, for --watch.
+
+use file_cleaner::PathError;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::{HashMap, VecDeque};
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// A file is handed out once nothing happened to it for this long, so one still being written isn't scanned half done.
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// Longest wait for an event before checking whether to stop.
+const POLL: Duration = Duration::from_millis(250);
+
+/// Paths of files created, modified or moved in under the watched directories, each once it settled.
+/// Ends once `stop` is set.
+pub struct Arrivals {
+    events: Receiver<notify::Result<notify::Event>>,
+    /// Kept for as long as the events are wanted, dropping it stops the watch.
+    _watcher: notify::RecommendedWatcher,
+    /// When each path last had an event.
+    pending: HashMap<PathBuf, Instant>,
+    settled: VecDeque<PathBuf>,
+    /// Which settled files to hand out, see [`Arrivals::admitting`].
+    admits: Box<dyn Fn(&Path) -> bool + Send>,
+    stop: &'static AtomicBool,
+}
+
+impl Arrivals {
+    /// Starts watching `dirs`, and with `recursive` whatever is created under them.
+    /// Call before walking them, so nothing that appears during the walk is missed.
+    pub fn new<'a>(
+        dirs: impl IntoIterator<Item = &'a Path>,
+        recursive: bool,
+        stop: &'static AtomicBool,
+    ) -> Result<Self, Error> {
+        let (sender, events) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender).map_err(Error::other)?;
+        let mode = if recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        for dir in dirs {
+            watcher
+                .watch(dir, mode)
+                .map_err(|err| PathError::wrap("watching", dir, Error::other(err)))?;
+        }
+        Ok(Self {
+            events,
+            _watcher: watcher,
+            pending: HashMap::new(),
+            settled: VecDeque::new(),
+            admits: Box::new(|_| true),
+            stop,
+        })
+    }
+
+    /// Only hands out the files `admits` accepts, e.g. [`file_cleaner::Walk::admits`].
+    pub fn admitting(mut self, admits: impl Fn(&Path) -> bool + Send + 'static) -> Self {
+        self.admits = Box::new(admits);
+        self
+    }
+
+    /// Moves every path that had no event for [`SETTLE`] to `settled`, if it's still a file.
+    fn settle(&mut self) {
+        let now = Instant::now();
+        self.pending.retain(|path, last| {
+            if now.duration_since(*last) < SETTLE {
+                return true;
+            }
+            // Gone already, e.g. a temporary file that was renamed, or a directory.
+            if std::fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) && (self.admits)(path) {
+                self.settled.push_back(path.clone());
+            }
+            false
+        });
+    }
+}
+
+impl Iterator for Arrivals {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            if self.stop.load(Relaxed) {
+                return None;
+            }
+            self.settle();
+            if let Some(path) = self.settled.pop_front() {
+                return Some(path);
+            }
+            let first_due = self.pending.values().min().map(|&last| last + SETTLE);
+            let timeout = first_due.map_or(POLL, |due| {
+                due.saturating_duration_since(Instant::now()).min(POLL)
+            });
+            match self.events.recv_timeout(timeout) {
+                Ok(Ok(event))
+                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) =>
+                {
+                    let now = Instant::now();
+                    self.pending
+                        .extend(event.paths.into_iter().map(|path| (path, now)));
+                }
+                Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+                Ok(Err(err)) => log::error!("watch: {err}"),
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
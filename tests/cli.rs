@@ -0,0 +1,216 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fs::write;
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+fn file_cleaner(dir: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_file_cleaner"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    output
+}
+
+/// A relative quarantine is still recognized in the paths of a scan from elsewhere, so what was moved there stays put.
+#[test]
+fn quarantine_is_not_rescanned() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"same contents").unwrap();
+    write(dir.path().join("b"), b"same contents").unwrap();
+
+    for _ in 0..2 {
+        file_cleaner(
+            dir.path(),
+            &["clean", "-r", "--delete-to", "q", "--keep", "oldest", "."],
+        );
+    }
+
+    let outside = ["a", "b"].map(|name| dir.path().join(name).exists());
+    assert_eq!(outside.iter().filter(|&&exists| exists).count(), 1);
+    assert!(!dir.path().join("q/q").exists());
+}
+
+/// An action given on the command line overrides delete-to in the config, which then leaves no quarantine behind.
+#[test]
+fn overridden_quarantine_is_not_created() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("c.toml"), "delete-to = \"q\"\n").unwrap();
+    write(dir.path().join("a"), b"same contents").unwrap();
+    write(dir.path().join("b"), b"same contents").unwrap();
+
+    file_cleaner(
+        dir.path(),
+        &["clean", "--config", "c.toml", "--hardlink", "."],
+    );
+
+    assert!(!dir.path().join("q").exists());
+    assert!(dir.path().join("a").exists() && dir.path().join("b").exists());
+}
+
+/// --by-name has no say in similarity or in hashing, so asking for it there is an error rather than silently ignored.
+#[test]
+fn by_name_is_rejected_where_names_are_ignored() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"same contents").unwrap();
+
+    for args in [
+        &["scan", "--by-name", "--similar", "."][..],
+        &["hash", "--by-name", "."],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_file_cleaner"))
+            .args(args)
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{args:?}: {output:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("--by-name"), "{args:?}: {stderr}");
+    }
+}
+
+/// A parallel walk of a wide tree opens directories as it lists them, so it needs no more file descriptors than threads.
+#[cfg(unix)]
+#[test]
+fn wide_trees_fit_in_few_file_descriptors() {
+    let dir = TempDir::new().unwrap();
+    for i in 0..500 {
+        let sub = dir.path().join(format!("d{i:03}"));
+        std::fs::create_dir(&sub).unwrap();
+        write(sub.join("f"), i.to_string()).unwrap();
+    }
+
+    let script = format!(
+        "ulimit -n 64 && exec '{}' scan -q -r --walk-threads 4 .",
+        env!("CARGO_BIN_EXE_file_cleaner")
+    );
+    let output = Command::new("sh")
+        .args(["-c", &script])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+}
+
+/// A name the log can't hold as it is would be restored under another one, so its file isn't acted upon.
+#[cfg(unix)]
+#[test]
+fn unlogged_names_are_left_alone() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = TempDir::new().unwrap();
+    let names =
+        [&b"a"[..], b"b\xff"].map(|name| dir.path().join(std::ffi::OsStr::from_bytes(name)));
+    for name in &names {
+        write(name, b"same contents").unwrap();
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_file_cleaner"))
+        .args(["clean", "--log", "log", "--sort", "name", "."])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+
+    assert!(names.iter().all(|name| name.exists()), "{output:?}");
+    assert_eq!(std::fs::read(dir.path().join("log")).unwrap(), b"");
+}
+
+/// Removes what `file_cleaner scan ... --emit-script s.sh` wrote to remove, as sh would run it.
+#[cfg(unix)]
+fn run_script(dir: &Path, args: &[&str]) -> String {
+    let args: Vec<_> = ["scan", "--emit-script", "s.sh"]
+        .iter()
+        .chain(args)
+        .copied()
+        .collect();
+    file_cleaner(dir, &args);
+    let script = std::fs::read_to_string(dir.join("s.sh")).unwrap();
+    let status = Command::new("sh")
+        .arg("s.sh")
+        .current_dir(dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{script}");
+    script
+}
+
+/// Names sh would take for something else are quoted so the script removes exactly them.
+#[cfg(unix)]
+#[test]
+fn script_quotes_every_name() {
+    let dir = TempDir::new().unwrap();
+    for name in ["keep", "-dash", "it's"] {
+        write(dir.path().join(name), b"same contents").unwrap();
+    }
+    write(dir.path().join("list"), "keep\n-dash\nit's\n").unwrap();
+    std::fs::create_dir(dir.path().join("n")).unwrap();
+    for name in ["a", "new\n$(line)"] {
+        write(dir.path().join("n").join(name), b"other contents").unwrap();
+    }
+
+    let script = run_script(dir.path(), &["--from-file", "list"]);
+    assert!(script.contains("rm -- '-dash'\n"), "{script}");
+    assert!(script.contains("rm -- 'it'\\''s'\n"), "{script}");
+    run_script(dir.path(), &["--sort", "name", "n"]);
+
+    let mut left: Vec<_> = (std::fs::read_dir(dir.path()).unwrap())
+        .chain(std::fs::read_dir(dir.path().join("n")).unwrap())
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    left.sort();
+    assert_eq!(left, ["a", "keep", "list", "n", "s.sh"]);
+}
+
+/// PowerShell takes typographic quotes as quotes too, so those are doubled like plain ones.
+#[cfg(windows)]
+#[test]
+fn script_quotes_every_name() {
+    let dir = TempDir::new().unwrap();
+    for name in ["keep", "-dash", "it's", "it\u{2019}s"] {
+        write(dir.path().join(name), b"same contents").unwrap();
+    }
+    write(dir.path().join("list"), "keep\n-dash\nit's\nit\u{2019}s\n").unwrap();
+
+    file_cleaner(
+        dir.path(),
+        &["scan", "--emit-script", "s.ps1", "--from-file", "list"],
+    );
+
+    let script = std::fs::read_to_string(dir.path().join("s.ps1")).unwrap();
+    for quoted in ["'-dash'", "'it''s'", "'it\u{2019}\u{2019}s'"] {
+        assert!(
+            script.contains(&format!("Remove-Item -LiteralPath {quoted}\n")),
+            "{script}"
+        );
+    }
+}
+
+/// Only what would be acted upon goes in the script, not copies --min-copies leaves alone.
+#[cfg(unix)]
+#[test]
+fn script_leaves_small_groups_alone() {
+    let dir = TempDir::new().unwrap();
+    for (name, contents) in [
+        ("p1", "pair"),
+        ("p2", "pair"),
+        ("t1", "trio"),
+        ("t2", "trio"),
+        ("t3", "trio"),
+    ] {
+        write(dir.path().join(name), contents).unwrap();
+    }
+
+    let script = run_script(dir.path(), &["--min-copies", "3", "--sort", "name", "."]);
+
+    assert_eq!(script.matches("rm -- ").count(), 2, "{script}");
+    assert!(dir.path().join("p2").exists());
+    assert!(dir.path().join("t1").exists() && !dir.path().join("t3").exists());
+}
@@ -0,0 +1,653 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use file_cleaner::{deduplicate, Options, Walk};
+use std::fs::{read_dir, write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn run(dir: &Path, options: &Options) -> file_cleaner::Summary {
+    deduplicate(Walk::new([dir], false).unwrap(), options).unwrap()
+}
+
+fn remaining(dir: &Path) -> usize {
+    read_dir(dir).unwrap().count()
+}
+
+#[test]
+fn two_identical_files_leave_one() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"same contents").unwrap();
+    write(dir.path().join("b"), b"same contents").unwrap();
+
+    let summary = run(dir.path(), &Options::default());
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(remaining(dir.path()), 1);
+}
+
+/// Deeper than Windows' legacy MAX_PATH of 260 characters.
+#[test]
+fn deep_paths_are_deduplicated() {
+    let root = TempDir::new().unwrap();
+    let dir = (0..30).fold(root.path().to_owned(), |dir, i| {
+        dir.join(format!("nested{i:02}"))
+    });
+    std::fs::create_dir_all(&dir).unwrap();
+    assert!(dir.as_os_str().len() > 260);
+    write(dir.join("a"), b"same contents").unwrap();
+    write(dir.join("b"), b"same contents").unwrap();
+
+    let summary = run(&dir, &Options::default());
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(remaining(&dir), 1);
+}
+
+/// Every copy shares one bucket, so the workers race for it; exactly one copy may survive.
+#[test]
+fn concurrent_identical_files_leave_one() {
+    let dir = TempDir::new().unwrap();
+    for i in 0..256 {
+        write(dir.path().join(i.to_string()), b"same contents").unwrap();
+    }
+    let options = Options {
+        threads: NonZeroUsize::new(8).unwrap(),
+        ..Default::default()
+    };
+
+    let summary = run(dir.path(), &options);
+
+    assert_eq!(summary.scanned, 256);
+    assert_eq!(summary.deleted, 255);
+    assert_eq!(remaining(dir.path()), 1);
+}
+
+/// The reference copy survives whichever copy is scanned first or [`Options::keep`] would pick.
+#[test]
+fn reference_files_are_never_removed() {
+    let reference = TempDir::new().unwrap();
+    let staging = TempDir::new().unwrap();
+    write(reference.path().join("a"), b"same contents").unwrap();
+    write(reference.path().join("b"), b"same contents").unwrap();
+    write(staging.path().join("a"), b"same contents").unwrap();
+    let options = Options {
+        references: vec![reference.path().to_owned()],
+        keep: file_cleaner::Keep::ShortestPath,
+        ..Default::default()
+    };
+
+    let walk = Walk::new([staging.path(), reference.path()], false).unwrap();
+    let summary = deduplicate(walk, &options).unwrap();
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(remaining(reference.path()), 2);
+    assert_eq!(remaining(staging.path()), 0);
+}
+
+/// One copy stays in each protected directory, however many copies there are elsewhere.
+#[test]
+fn protected_directories_keep_a_copy() {
+    let library = TempDir::new().unwrap();
+    let other = TempDir::new().unwrap();
+    let scratch = TempDir::new().unwrap();
+    write(library.path().join("a"), b"same contents").unwrap();
+    write(library.path().join("b"), b"same contents").unwrap();
+    write(other.path().join("a"), b"same contents").unwrap();
+    write(scratch.path().join("a"), b"same contents").unwrap();
+    let options = Options {
+        protect: vec![library.path().to_owned(), other.path().to_owned()],
+        keep: file_cleaner::Keep::ShortestPath,
+        ..Default::default()
+    };
+
+    let walk = Walk::new([scratch.path(), library.path(), other.path()], false).unwrap();
+    let summary = deduplicate(walk, &options).unwrap();
+
+    assert_eq!(summary.deleted, 2);
+    assert_eq!(remaining(library.path()), 1);
+    assert_eq!(remaining(other.path()), 1);
+    assert_eq!(remaining(scratch.path()), 0);
+}
+
+/// Only directories emptied by the run go, their parents with them; the root and anything empty before stay.
+#[test]
+fn emptied_directories_are_pruned() {
+    let root = TempDir::new().unwrap();
+    std::fs::create_dir_all(root.path().join("a/b")).unwrap();
+    std::fs::create_dir(root.path().join("empty")).unwrap();
+    write(root.path().join("x"), b"same contents").unwrap();
+    write(root.path().join("a/b/y"), b"same contents").unwrap();
+    let options = Options {
+        keep: file_cleaner::Keep::ShortestPath,
+        prune_empty_dirs: vec![root.path().to_owned()],
+        ..Default::default()
+    };
+
+    let summary = deduplicate(Walk::new([root.path()], true).unwrap(), &options).unwrap();
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(summary.pruned_dirs, 2);
+    assert!(!root.path().join("a").exists());
+    assert!(root.path().join("empty").exists());
+}
+
+/// A second run's duplicate at the same path gets a new name rather than overwriting the first.
+#[test]
+fn quarantined_duplicates_are_never_overwritten() {
+    let dir = TempDir::new().unwrap();
+    let quarantine = TempDir::new().unwrap();
+    let options = Options {
+        action: file_cleaner::Action::Quarantine,
+        quarantine: Some(quarantine.path().to_owned()),
+        keep: file_cleaner::Keep::ShortestPath,
+        ..Default::default()
+    };
+    let relative = (dir.path().components())
+        .filter(|component| matches!(component, std::path::Component::Normal(_)));
+    let moved = quarantine
+        .path()
+        .join(relative.collect::<std::path::PathBuf>());
+
+    for contents in [b"first contents", b"other contents"] {
+        write(dir.path().join("a"), contents).unwrap();
+        write(dir.path().join("a.txt"), contents).unwrap();
+        assert_eq!(run(dir.path(), &options).deleted, 1);
+    }
+
+    assert_eq!(remaining(dir.path()), 1);
+    assert_eq!(
+        std::fs::read(moved.join("a.txt")).unwrap(),
+        b"first contents"
+    );
+    assert_eq!(
+        std::fs::read(moved.join("a-1.txt")).unwrap(),
+        b"other contents"
+    );
+}
+
+/// A root reached through `..` still gets links that resolve, whichever of the copies becomes one.
+#[cfg(unix)]
+#[test]
+fn symlinks_resolve_from_dotted_roots() {
+    let dir = TempDir::new().unwrap();
+    for sub in ["k", "d", "sub"] {
+        std::fs::create_dir(dir.path().join(sub)).unwrap();
+    }
+    write(dir.path().join("k/a"), b"same contents").unwrap();
+    write(dir.path().join("d/b"), b"same contents").unwrap();
+    let options = Options {
+        action: file_cleaner::Action::Symlink,
+        ..Default::default()
+    };
+
+    let roots = [dir.path().join("k"), dir.path().join("sub/../d")];
+    let summary = deduplicate(Walk::new(roots, false).unwrap(), &options).unwrap();
+
+    assert_eq!((summary.deleted, summary.errors), (1, 0));
+    for path in ["k/a", "d/b"] {
+        assert_eq!(
+            std::fs::read(dir.path().join(path)).unwrap(),
+            b"same contents"
+        );
+    }
+}
+
+/// However many workers race for them, the first path in the sorted order survives.
+#[test]
+fn sorted_runs_keep_the_first_path() {
+    let dir = TempDir::new().unwrap();
+    for name in ["d", "c", "b", "a"] {
+        write(dir.path().join(name), b"same contents").unwrap();
+    }
+    let options = Options {
+        sort: Some(file_cleaner::Sort::Path),
+        threads: NonZeroUsize::new(4).unwrap(),
+        ..Default::default()
+    };
+
+    let summary = run(dir.path(), &options);
+
+    assert_eq!(summary.deleted, 3);
+    assert!(dir.path().join("a").exists());
+}
+
+/// Only copies under a directory deletion is allowed in are removed, whichever copy the policy would keep.
+#[test]
+fn deletes_only_under_allowed_directories() {
+    let root = TempDir::new().unwrap();
+    std::fs::create_dir(root.path().join("scratch")).unwrap();
+    write(root.path().join("a"), b"same contents").unwrap();
+    write(root.path().join("b"), b"same contents").unwrap();
+    write(root.path().join("scratch/c"), b"same contents").unwrap();
+    let options = Options {
+        keep: file_cleaner::Keep::ShortestPath,
+        delete_under: vec![root.path().join("scratch")],
+        ..Default::default()
+    };
+
+    let summary = deduplicate(Walk::new([root.path()], true).unwrap(), &options).unwrap();
+
+    assert_eq!(summary.deleted, 1);
+    assert!(root.path().join("a").exists());
+    assert!(root.path().join("b").exists());
+    assert!(!root.path().join("scratch/c").exists());
+}
+
+/// With --same-ext-only a copy under another extension is kept, while one with the same extension still goes.
+#[test]
+fn other_extensions_are_left_alone() {
+    let dir = TempDir::new().unwrap();
+    for name in ["backup.tar", "backup.tar.bak", "copy.TAR"] {
+        write(dir.path().join(name), b"same contents").unwrap();
+    }
+    let options = Options {
+        same_ext_only: true,
+        ..Default::default()
+    };
+
+    let summary = run(dir.path(), &options);
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(summary.other_ext, 1);
+    assert!(dir.path().join("backup.tar.bak").exists());
+}
+
+/// Files starting with zeros only still share an ID with none but files of their size, and same-size copies are still found.
+#[test]
+fn zero_prefixes_are_told_apart_by_size() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), vec![0u8; 8192]).unwrap();
+    write(dir.path().join("b"), vec![0u8; 8192]).unwrap();
+    write(dir.path().join("c"), vec![0u8; 16384]).unwrap();
+
+    let summary = run(dir.path(), &Options::default());
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(summary.zero_prefix, 3);
+    assert_eq!(remaining(dir.path()), 2);
+}
+
+/// Given hashes group files without hashing them, and a wrong one still doesn't get a different file deleted.
+#[test]
+fn given_hashes_are_confirmed_byte_for_byte() {
+    let dir = TempDir::new().unwrap();
+    for (name, contents) in [
+        ("a", "same contents"),
+        ("b", "same contents"),
+        ("c", "diff contents"),
+    ] {
+        write(dir.path().join(name), contents).unwrap();
+    }
+    let files = ["a", "b", "c"].map(|name| ([7; 32], dir.path().join(name)));
+
+    let summary = file_cleaner::deduplicate_hashed(files.into_iter(), &Options::default()).unwrap();
+
+    assert_eq!(summary.deleted, 1);
+    assert!(dir.path().join("c").exists());
+    assert_eq!(remaining(dir.path()), 2);
+}
+
+/// Groups with fewer copies than asked for are left alone, and not reported as acted upon either.
+#[test]
+fn groups_below_min_copies_are_not_reported() {
+    let dir = TempDir::new().unwrap();
+    for (name, contents) in [
+        ("a", "pair"),
+        ("b", "pair"),
+        ("c", "trio"),
+        ("d", "trio"),
+        ("e", "trio"),
+    ] {
+        write(dir.path().join(name), contents).unwrap();
+    }
+    let options = Options {
+        min_copies: 3,
+        groups: true,
+        ..Default::default()
+    };
+
+    let summary = run(dir.path(), &options);
+
+    assert_eq!((summary.found, summary.deleted), (2, 2));
+    assert_eq!(summary.groups.len(), 1);
+    assert_eq!(summary.groups[0].duplicates.len(), 2);
+    assert_eq!(remaining(dir.path()), 3);
+}
+
+/// A header every file starts with doesn't make them alike, nor hide the two that are.
+#[test]
+fn shared_headers_are_not_similarity() {
+    let dir = TempDir::new().unwrap();
+    let header = "timestamp,level,message\n".repeat(100);
+    let body = |seed: u64| {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        let mut body = header.clone().into_bytes();
+        for _ in 0..4096 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            body.push(b'a' + (state % 26) as u8);
+        }
+        body
+    };
+    for i in 0..300 {
+        write(dir.path().join(format!("log{i:03}")), body(i)).unwrap();
+    }
+    let mut edited = body(7);
+    edited[3000] = b'!';
+    write(dir.path().join("edited"), edited).unwrap();
+
+    let walk = Walk::new([dir.path()], false).unwrap();
+    let summary = file_cleaner::find_similar(walk, 0.8, &Options::default());
+
+    assert_eq!(summary.scanned, 301);
+    assert_eq!(summary.groups.len(), 1);
+    let mut names: Vec<_> = (summary.groups[0].files.iter())
+        .map(|(path, _)| path.file_name().unwrap().to_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["edited", "log007"]);
+}
+
+/// Files handed to a watch after the walk are compared with what it found.
+#[test]
+fn arrivals_are_compared_with_earlier_files() {
+    let dir = TempDir::new().unwrap();
+    let inbox = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"same contents").unwrap();
+    let arrival = inbox.path().join("b");
+    write(&arrival, b"same contents").unwrap();
+
+    let walk = Walk::new([dir.path()], false).unwrap();
+    let summary =
+        file_cleaner::watch(walk, [arrival.clone()].into_iter(), &Options::default()).unwrap();
+
+    assert_eq!(summary.deleted, 1);
+    assert!(!arrival.exists());
+    assert_eq!(remaining(dir.path()), 1);
+}
+
+/// A duplicate recreated where one was removed arrives under a path scanned before, and goes as well.
+#[test]
+fn recreated_duplicates_are_scanned_again() {
+    let dir = TempDir::new().unwrap();
+    for name in ["a", "b"] {
+        write(dir.path().join(name), b"same contents").unwrap();
+    }
+    let mut recreated = false;
+    let arrivals = std::iter::from_fn(|| {
+        let path = ["a", "b"]
+            .map(|name| dir.path().join(name))
+            .into_iter()
+            .find(|path| !path.exists())?;
+        (!std::mem::replace(&mut recreated, true)).then(|| {
+            write(&path, b"same contents").unwrap();
+            path
+        })
+    });
+
+    let options = Options {
+        threads: NonZeroUsize::new(1).unwrap(),
+        ..Options::default()
+    };
+    let walk = Walk::new([dir.path()], false).unwrap();
+    let summary = file_cleaner::watch(walk, arrivals, &options).unwrap();
+
+    assert_eq!(summary.deleted, 2);
+    assert_eq!(remaining(dir.path()), 1);
+}
+
+/// Arrivals are filtered as the walk filters what it finds, here under a skipped and a hidden directory.
+#[test]
+fn arrivals_skip_what_the_walk_skips() {
+    let dir = TempDir::new().unwrap();
+    for sub in ["node_modules", ".git", "src"] {
+        std::fs::create_dir(dir.path().join(sub)).unwrap();
+    }
+    write(dir.path().join("a"), b"same contents").unwrap();
+    let walk = (Walk::new([dir.path()], true).unwrap()).skip_dirs(vec!["node_modules".into()]);
+    let admits = walk.admits();
+    let arrivals = ["node_modules/b", ".git/b", "src/b"].map(|file| {
+        let path = dir.path().join(file);
+        write(&path, b"same contents").unwrap();
+        path
+    });
+
+    let arrivals = arrivals.into_iter().filter(|path| admits(path));
+    let summary = file_cleaner::watch(walk, arrivals, &Options::default()).unwrap();
+
+    assert_eq!(summary.deleted, 1); // of a or src/b, whichever came second
+    assert!(dir.path().join("node_modules/b").exists());
+    assert!(dir.path().join(".git/b").exists());
+}
+
+/// Listing directories on several threads finds the same files as listing them in turn.
+#[test]
+fn parallel_walk_finds_every_file() {
+    let root = TempDir::new().unwrap();
+    for dir in ["a/b/c", "a/d", "e"] {
+        std::fs::create_dir_all(root.path().join(dir)).unwrap();
+        for name in ["1", "2", "3"] {
+            write(root.path().join(dir).join(name), dir).unwrap();
+        }
+    }
+
+    let mut walked: Vec<_> = Walk::new([root.path()], true).unwrap().collect();
+    let threads = NonZeroUsize::new(4).unwrap();
+    let mut parallel: Vec<_> = (Walk::new([root.path()], true).unwrap().threads(threads)).collect();
+    walked.sort();
+    parallel.sort();
+
+    assert_eq!(walked.len(), 9);
+    assert_eq!(parallel, walked);
+}
+
+/// A depth limit stops descending that many levels below the root, with or without walk threads.
+#[test]
+fn max_depth_limits_recursion() {
+    let root = TempDir::new().unwrap();
+    std::fs::create_dir_all(root.path().join("a/b")).unwrap();
+    for file in ["1", "a/2", "a/b/3"] {
+        write(root.path().join(file), file).unwrap();
+    }
+
+    for (depth, found) in [(0, 1), (1, 2), (2, 3)] {
+        for threads in [1, 4] {
+            let walk = (Walk::new([root.path()], true).unwrap())
+                .max_depth(Some(depth))
+                .threads(NonZeroUsize::new(threads).unwrap());
+            assert_eq!(walk.count(), found, "depth {depth} on {threads} threads");
+        }
+    }
+}
+
+/// A root that can't be read says which one, and what was being done with it.
+#[test]
+fn missing_root_is_named() {
+    let dir = TempDir::new().unwrap();
+    let missing = dir.path().join("missing");
+
+    let Err(err) = Walk::new([&missing], false) else {
+        panic!("a missing root was walked");
+    };
+
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    let context = file_cleaner::PathError::of(&err).unwrap();
+    assert_eq!(context.path, missing);
+    assert_eq!(context.op, Some("reading directory"));
+}
+
+/// A copy whose path matches --keep-path wins over the one the policy would keep.
+#[test]
+fn matching_path_is_kept() {
+    let dir = TempDir::new().unwrap();
+    for sub in ["a", "canonical", "z"] {
+        std::fs::create_dir(dir.path().join(sub)).unwrap();
+        write(dir.path().join(sub).join("copy"), b"same contents").unwrap();
+    }
+    let options = Options {
+        sort: Some(file_cleaner::Sort::Path),
+        keep_path: Some(regex::Regex::new("/canonical/").unwrap()),
+        ..Default::default()
+    };
+
+    let summary = deduplicate(Walk::new([dir.path()], true).unwrap(), &options).unwrap();
+
+    assert_eq!(summary.deleted, 2);
+    assert!(dir.path().join("canonical/copy").exists());
+}
+
+/// Scanning size class by size class finds the same copies, and still reports every group.
+#[test]
+fn sharded_runs_find_every_copy() {
+    let dir = TempDir::new().unwrap();
+    for (name, contents) in [
+        ("a", "one"),
+        ("b", "one"),
+        ("c", "three"),
+        ("d", "three"),
+        ("e", "three"),
+        ("f", "unique"),
+    ] {
+        write(dir.path().join(name), contents).unwrap();
+    }
+    let options = Options {
+        shard_by_size: true,
+        groups: true,
+        ..Default::default()
+    };
+
+    let summary = run(dir.path(), &options);
+
+    assert_eq!(summary.deleted, 3);
+    assert_eq!(summary.groups.len(), 2);
+    assert_eq!(remaining(dir.path()), 3);
+}
+
+/// Files that only differ at the end are told apart without a full read, and copies are still found.
+#[test]
+fn bloom_filter_only_rules_out() {
+    let dir = TempDir::new().unwrap();
+    let mut contents = vec![b'x'; 100_000];
+    write(dir.path().join("a"), &contents).unwrap();
+    write(dir.path().join("b"), &contents).unwrap();
+    *contents.last_mut().unwrap() = b'y';
+    write(dir.path().join("c"), &contents).unwrap();
+    let options = Options {
+        bloom: true,
+        ..Default::default()
+    };
+
+    let summary = run(dir.path(), &options);
+
+    assert_eq!(summary.deleted, 1);
+    assert!(summary.ruled_out >= 1);
+    assert_eq!(remaining(dir.path()), 2);
+}
+
+#[test]
+fn unique_files_are_kept() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"first contents").unwrap();
+    write(dir.path().join("b"), b"other contents").unwrap();
+    write(dir.path().join("c"), b"longer than the others").unwrap();
+
+    let summary = run(dir.path(), &Options::default());
+
+    assert_eq!(summary.found, 0);
+    assert_eq!(summary.deleted, 0);
+    assert_eq!(remaining(dir.path()), 3);
+}
+
+/// Files this short can't fill an ID's prefix, they're hashed as they are.
+#[test]
+fn short_identical_files_leave_one() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"abc").unwrap();
+    write(dir.path().join("b"), b"abc").unwrap();
+    write(dir.path().join("c"), b"abd").unwrap();
+
+    let summary = run(dir.path(), &Options::default());
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(remaining(dir.path()), 2);
+}
+
+/// IDs only cover the start of a file, so these share a bucket and must be told apart by the rest.
+#[test]
+fn same_prefix_different_contents_are_kept() {
+    let dir = TempDir::new().unwrap();
+    let mut a = vec![7u8; 64 * 1024];
+    let mut b = a.clone();
+    a.push(1);
+    b.push(2);
+    write(dir.path().join("a"), &a).unwrap();
+    write(dir.path().join("b"), &b).unwrap();
+    write(dir.path().join("c"), &a).unwrap();
+
+    let summary = run(dir.path(), &Options::default());
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), b);
+    assert_eq!(remaining(dir.path()), 2);
+}
+
+#[test]
+fn empty_files_are_skipped_by_default() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"").unwrap();
+    write(dir.path().join("b"), b"").unwrap();
+
+    let summary = run(dir.path(), &Options::default());
+
+    assert_eq!(summary.empty, 2);
+    assert_eq!(summary.deleted, 0);
+    assert_eq!(remaining(dir.path()), 2);
+
+    let options = Options {
+        empty: file_cleaner::Empty::Dedup,
+        ..Default::default()
+    };
+    let summary = run(dir.path(), &options);
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(remaining(dir.path()), 1);
+}
+
+#[test]
+fn dry_run_leaves_every_copy() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"same contents").unwrap();
+    write(dir.path().join("b"), b"same contents").unwrap();
+    let options = Options {
+        dry_run: true,
+        ..Default::default()
+    };
+
+    let summary = run(dir.path(), &options);
+
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(summary.bytes_freed, 13);
+    assert_eq!(remaining(dir.path()), 2);
+}
+
+#[test]
+fn hashing_touches_nothing() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path().join("a"), b"same contents").unwrap();
+    write(dir.path().join("b"), b"same contents").unwrap();
+    write(dir.path().join("c"), b"").unwrap();
+
+    let walk = Walk::new([dir.path()], false).unwrap();
+    let summary = file_cleaner::hash_files(walk, &Options::default()).unwrap();
+
+    assert_eq!(summary.hashes.len(), 3);
+    assert_eq!(summary.hashes[0].hash, summary.hashes[1].hash);
+    assert_eq!(summary.hashes[2].hash, *blake3::hash(b"").as_bytes());
+    assert_eq!(remaining(dir.path()), 3);
+}